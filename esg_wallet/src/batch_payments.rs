@@ -0,0 +1,218 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::{caller, query, update};
+use icrc_ledger_types::icrc1::account::Account;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::cawa_poster::{caller_may_contribute, post_contribution, ticket_co2e, validate_client};
+use crate::esg_wallet::{attributed_client, get_price, record_payment, transfer_from_payer};
+
+const MAX_TICKET_COUNT: u64 = 1000000;
+const MAX_ALLOCATIONS: usize = 100;
+
+/// Share of a batch purchase, attributed either to a node or to a client.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BatchAllocation {
+    pub node_id: Option<String>,
+    pub client: Option<String>,
+    pub ticket_count: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BatchPayment {
+    pub id: u64,
+    pub payer: Principal,
+    pub block_height: Nat,
+    pub ticket_count: u64,
+    pub total_price: f64,
+    pub allocations: Vec<BatchAllocation>,
+    // child payments, in the same order as the allocations
+    pub payment_ids: Vec<u64>,
+    pub created_at: u64,
+}
+
+thread_local! {
+    static BATCH_PAYMENTS: RefCell<BTreeMap<u64, BatchPayment>> = RefCell::default();
+}
+
+pub fn batch_payments_snapshot() -> BTreeMap<u64, BatchPayment> {
+    BATCH_PAYMENTS.with(|b| b.borrow().clone())
+}
+
+pub fn restore_batch_payments(batch_payments: BTreeMap<u64, BatchPayment>) {
+    BATCH_PAYMENTS.with(|b| *b.borrow_mut() = batch_payments);
+}
+
+fn validate_allocations(allocations: &[BatchAllocation]) -> Result<u64, String> {
+    if allocations.is_empty() {
+        return Err("At least one allocation is required".to_string());
+    }
+    if allocations.len() > MAX_ALLOCATIONS {
+        return Err(format!("At most {} allocations are allowed", MAX_ALLOCATIONS));
+    }
+
+    // the child payments share the block height of the batch, so the node_manager could only tell
+    // two allocations to the same target apart by their payment id and would apply just one
+    let mut targets = BTreeSet::new();
+    let mut ticket_count: u64 = 0;
+    for allocation in allocations {
+        if allocation.node_id.is_some() == allocation.client.is_some() {
+            return Err("Each allocation needs either a node_id or a client".to_string());
+        }
+        if !targets.insert((&allocation.node_id, &allocation.client)) {
+            return Err("Each node or client can only be allocated once per batch".to_string());
+        }
+        if allocation.ticket_count == 0 {
+            return Err("Invalid ticket count".to_string());
+        }
+        if let Some(client) = &allocation.client {
+            validate_client(client)?;
+        }
+        ticket_count = ticket_count.saturating_add(allocation.ticket_count);
+    }
+
+    if ticket_count > MAX_TICKET_COUNT {
+        return Err("Ticket count is too big".to_string());
+    }
+    Ok(ticket_count)
+}
+
+/// Buys tickets for several nodes or clients with a single ICRC-2 transfer. Each allocation
+/// gets its own Cawa contribution and child payment linked to the batch.
+#[update(name = "registerBatchPayment")]
+async fn register_batch_payment(allocations: Vec<BatchAllocation>) -> Result<BatchPayment, String> {
+    if !caller_may_contribute() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    let ticket_count = validate_allocations(&allocations)?;
+    let total_price = get_price(ticket_count as f64);
+    let payer = caller();
+    let from = Account {
+        owner: payer,
+        subaccount: None,
+    };
+
+    let block_height = transfer_from_payer(from, total_price as u64).await?;
+
+    // reserve the batch id before the contributions are sent, they await on Cawa
+    let batch_id = BATCH_PAYMENTS.with(|b| {
+        let mut batch_payments = b.borrow_mut();
+        let id = batch_payments.keys().next_back().map_or(1, |id| id + 1);
+        batch_payments.insert(
+            id,
+            BatchPayment {
+                id,
+                payer,
+                block_height: block_height.clone(),
+                ticket_count,
+                total_price,
+                allocations: allocations.clone(),
+                payment_ids: vec![],
+                created_at: ic_cdk::api::time(),
+            },
+        );
+        id
+    });
+
+    // unlike contribute, a node allocation always posts a contribution for its attributed client,
+    // every allocation is paid for
+    for allocation in &allocations {
+        let client = match &allocation.client {
+            Some(client) => client.clone(),
+            None => attributed_client(&allocation.node_id),
+        };
+        let contribution_id = post_contribution(client.clone(), ticket_co2e(allocation.ticket_count)).await;
+        let (payment_id, _) = record_payment(
+            payer,
+            block_height.clone(),
+            allocation.ticket_count,
+            allocation.node_id.clone(),
+            client,
+            contribution_id,
+            Some(batch_id),
+        )
+        .await;
+        BATCH_PAYMENTS.with(|b| {
+            if let Some(batch) = b.borrow_mut().get_mut(&batch_id) {
+                batch.payment_ids.push(payment_id);
+            }
+        });
+    }
+
+    BATCH_PAYMENTS.with(|b| {
+        b.borrow()
+            .get(&batch_id)
+            .cloned()
+            .ok_or_else(|| format!("Batch payment {} not found", batch_id))
+    })
+}
+
+// batch payments made by the caller
+#[query(name = "getBatchPayments")]
+fn get_batch_payments() -> Vec<BatchPayment> {
+    let caller = caller();
+    BATCH_PAYMENTS.with(|b| {
+        b.borrow()
+            .values()
+            .filter(|batch| batch.payer == caller)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query(name = "getBatchPayment")]
+fn get_batch_payment(batch_id: u64) -> Option<BatchPayment> {
+    BATCH_PAYMENTS.with(|b| b.borrow().get(&batch_id).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(node_id: &str, ticket_count: u64) -> BatchAllocation {
+        BatchAllocation {
+            node_id: Some(node_id.to_string()),
+            client: None,
+            ticket_count,
+        }
+    }
+
+    fn client(client: &str, ticket_count: u64) -> BatchAllocation {
+        BatchAllocation {
+            node_id: None,
+            client: Some(client.to_string()),
+            ticket_count,
+        }
+    }
+
+    #[test]
+    fn sums_the_tickets_of_valid_allocations() {
+        assert_eq!(validate_allocations(&[node("a", 2), client("acme", 3)]), Ok(5));
+    }
+
+    #[test]
+    fn rejects_invalid_allocations() {
+        assert!(validate_allocations(&[]).is_err());
+        assert!(validate_allocations(&[node("a", 0)]).is_err());
+        assert!(validate_allocations(&[node("a", MAX_TICKET_COUNT), client("acme", 1)]).is_err());
+        assert!(validate_allocations(&[client("acme corp", 1)]).is_err());
+        let both = BatchAllocation {
+            node_id: Some("a".to_string()),
+            client: Some("acme".to_string()),
+            ticket_count: 1,
+        };
+        assert!(validate_allocations(&[both]).is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_targets() {
+        assert!(validate_allocations(&[node("a", 1), node("a", 2)]).is_err());
+        assert!(validate_allocations(&[client("acme", 1), client("acme", 2)]).is_err());
+        // a node and a client of the same name are different targets
+        assert_eq!(validate_allocations(&[node("acme", 1), client("acme", 2)]), Ok(3));
+    }
+}
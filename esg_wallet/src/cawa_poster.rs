@@ -14,6 +14,21 @@ use candid::Principal;
 use std::collections::HashSet;
 use serde_json::json;
 
+use crate::units::{Co2e, Co2eUnit};
+
+// the Cawa project every contribution is made to
+pub const CAWA_PROJECT_ID: &str = "018828f6-8718-4550-9c6e-83a0fa52402d";
+pub const CAWA_VENDOR: &str = "Cawa";
+// contributions are made in whole kilos, one ticket is one kilo of CO2e
+const CAWA_UNIT: Co2eUnit = Co2eUnit::Kilograms;
+// clients end up in the on_behalf_of address of a contribution
+const MAX_CLIENT_LENGTH: usize = 64;
+
+// CO2e a number of Cawa tickets offsets
+pub fn ticket_co2e(ticket_count: u64) -> Co2e {
+    Co2e::new(ticket_count, CAWA_UNIT)
+}
+
 
 
 
@@ -21,7 +36,7 @@ use serde_json::json;
 #[derive(Serialize, Deserialize)]
 struct Context {
     project_id: String,
-    ticket_count: f64,
+    amount: Co2e,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -118,26 +133,63 @@ pub fn deauthorize(principal: Principal) {
 }
 
 
+// Whether the caller may have contributions posted to Cawa: any principal while none is
+// authorized, else only the authorized ones. Purchases check it before charging, as the
+// contribution is posted later on, also from timers.
+pub(crate) fn caller_may_contribute() -> bool {
+    let caller = caller();
+    AUTHORIZED_PRINCIPALS.with(|p| {
+        let authorized_principals = p.borrow();
+        authorized_principals.is_empty() || authorized_principals.contains(&caller)
+    })
+}
+
 #[update]
 pub async fn send(client: String, ticket_count: f64) -> String {
 
     // check if the caller is authorized
-    let caller = caller(); 
-    let is_authorized = AUTHORIZED_PRINCIPALS.with(|p| {
-        let authorized_principals = p.borrow();
-        authorized_principals.is_empty() || authorized_principals.contains(&caller)
-    });
-
-    if !is_authorized {
+    if !caller_may_contribute() {
         return serde_json::to_string(&json!({"error": "Unauthorized: the caller is not allowed to perform this action."})).unwrap();
     }
-    
+
+    match post_contribution(client, ticket_co2e(ticket_count as u64)).await {
+        Ok(contribution_id) => contribution_id,
+        Err(e) => serde_json::to_string(&json!({"error": e})).unwrap(),
+    }
+}
+
+// sends a contribution of a CO2e amount, which has to be a whole number of kilos
+#[update(name = "sendCo2e")]
+pub async fn send_co2e(client: String, amount: Co2e) -> Result<String, String> {
+    if !caller_may_contribute() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    post_contribution(client, amount).await
+}
+
+// a client name has to fit in the local part of the on_behalf_of address
+pub(crate) fn validate_client(client: &str) -> Result<(), String> {
+    if client.is_empty() || client.len() > MAX_CLIENT_LENGTH {
+        return Err(format!("Client must have between 1 and {} characters", MAX_CLIENT_LENGTH));
+    }
+    if !client.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err("Client may only contain ASCII letters, digits, '-', '_' and '.'".to_string());
+    }
+    Ok(())
+}
+
+// posts a prepaid contribution to Cawa on behalf of a client and returns the contribution id,
+// callers are responsible for authorization (the settlement pipeline also runs from timers)
+pub async fn post_contribution(client: String, amount: Co2e) -> Result<String, String> {
+    validate_client(&client)?;
     let host = "api.cawa.tech";
     let url = "https://api.cawa.tech/api/v1/contribution/prepaid";
-    let project_id = "018828f6-8718-4550-9c6e-83a0fa52402d";
+    let project_id = CAWA_PROJECT_ID;
     let api_key = API_KEY.with(|k| k.borrow().clone());
     
-    let ticket_count_u64 = ticket_count as u64;
+    let Some(amount_in_unit) = amount.whole(CAWA_UNIT) else {
+        return Err(format!("Cawa only takes whole kilos, cannot contribute {} g", amount.grams));
+    };
    
 
     let idempotency_key = generate_uuid();
@@ -162,20 +214,21 @@ pub async fn send(client: String, ticket_count: f64) -> String {
 
 
     let request_body_json = ContributionRequest {
-        amount: ticket_count_u64,
+        amount: amount_in_unit,
         on_behalf_of: format!("cawa+{}@carboncrowd.io", client).to_string(),
         unit: "kilos".to_string(),
         currency: "EUR".to_string(),
         project: project_id.to_string(),
     };
 
-    let json_string = serde_json::to_string(&request_body_json).expect("Failed to serialize request body");
+    let json_string = serde_json::to_string(&request_body_json)
+        .map_err(|e| format!("Failed to serialize request body: {}", e))?;
     let json_utf8: Vec<u8> = json_string.into_bytes();
     let request_body: Option<Vec<u8>> = Some(json_utf8);
 
     let context = Context {
         project_id: project_id.to_string(),
-        ticket_count,
+        amount,
     };
 
     let request = CanisterHttpRequestArgument {
@@ -189,52 +242,52 @@ pub async fn send(client: String, ticket_count: f64) -> String {
                 principal: ic_cdk::api::id(),
                 method: "transform".to_string(),
             }),
-            context: serde_json::to_vec(&context).unwrap(),
+            context: serde_json::to_vec(&context)
+                .map_err(|e| format!("Failed to serialize transform context: {}", e))?,
         }),
     };
 
+    // the payer has already been charged when this is called, so nothing on the response path
+    // may trap: an error leaves the payment without a proof until the proof retry picks it up
     match http_request(request, 21_000_000_000).await {
         Ok((response,)) => {
             let str_body = String::from_utf8(response.body)
-            .expect("Transformed response is not UTF-8 encoded.");
+                .map_err(|e| format!("Cawa response is not UTF-8 encoded: {}", e))?;
 
             ic_cdk::api::print(format!("Response from cawa: {}", str_body));
-            
-        // Check if the response status code indicates an error
-        if response.status >= 400u32 && response.status < 600u32 {
-            // Parse the error message from the response body
-            let parsed: serde_json::Value = match serde_json::from_str(&str_body) {
-                Ok(value) => value,
-                Err(e) => ic_cdk::trap(&format!("Failed to parse error response as JSON: {:?}", e)),
-            };
-            let error_message = parsed["error"].as_str().unwrap_or("Unknown error");
-            ic_cdk::trap(&format!("CAWA API error: {:?}", error_message));
-        }
-        
-        // Parse the JSON response
-        let parsed: serde_json::Value = serde_json::from_str(&str_body)
-            .expect("JSON was not well-formatted");
 
-        // Extract the id field
-        let id_array = parsed["id"].as_array()
-            .expect("id field not found or not an array");
-
-        // Get the first element of the array
-        let id = id_array.get(0)
-            .and_then(|v| v.as_str())
-            .expect("id array is empty or contains non-string");
-
-        id.to_string()
+            if response.status >= 400u32 && response.status < 600u32 {
+                return Err(contribution_error(&str_body));
+            }
+            contribution_id(&str_body)
         }
         Err((r, m)) => {
-            let message =
-                format!("The http_request resulted into error. RejectionCode: {r:?}, Error: {m}");
-
-            message
+            Err(format!("The http_request resulted into error. RejectionCode: {r:?}, Error: {m}"))
         }
     }
 }
 
+// error message of a failed contribution, the body may not even be JSON
+fn contribution_error(body: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(parsed) => format!("CAWA API error: {:?}", parsed["error"].as_str().unwrap_or("Unknown error")),
+        Err(e) => format!("Failed to parse error response as JSON: {:?}", e),
+    }
+}
+
+// the contribution id is the first element of the id array of the response
+fn contribution_id(body: &str) -> Result<String, String> {
+    let parsed: serde_json::Value =
+        serde_json::from_str(body).map_err(|e| format!("Cawa response is not well-formatted JSON: {}", e))?;
+    parsed["id"]
+        .as_array()
+        .and_then(|ids| ids.first())
+        .and_then(|id| id.as_str())
+        .filter(|id| !id.is_empty())
+        .map(|id| id.to_string())
+        .ok_or_else(|| "Cawa response has no contribution id".to_string())
+}
+
 #[query]
 fn transform(raw: TransformArgs) -> HttpResponse {
     let headers = vec![HttpHeader {
@@ -382,3 +435,33 @@ pub async fn get_contribution_by_id(contribution_id: String) -> String {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_contribution_id() {
+        assert_eq!(contribution_id(r#"{"id": ["abc", "def"]}"#), Ok("abc".to_string()));
+    }
+
+    #[test]
+    fn malformed_responses_are_errors() {
+        assert!(contribution_id("not json").is_err());
+        assert!(contribution_id(r#"{"id": "abc"}"#).is_err());
+        assert!(contribution_id(r#"{"id": []}"#).is_err());
+        assert!(contribution_id(r#"{"id": [""]}"#).is_err());
+        assert!(contribution_id(r#"{"id": [1]}"#).is_err());
+        assert_eq!(contribution_error(r#"{"error": "no funds"}"#), "CAWA API error: \"no funds\"");
+        assert!(contribution_error("<html>").starts_with("Failed to parse"));
+    }
+
+    #[test]
+    fn validates_clients() {
+        assert!(validate_client("openchat").is_ok());
+        assert!(validate_client("a-b_c.d").is_ok());
+        assert!(validate_client("").is_err());
+        assert!(validate_client("x@evil.com").is_err());
+        assert!(validate_client(&"a".repeat(MAX_CLIENT_LENGTH + 1)).is_err());
+    }
+}
@@ -0,0 +1,351 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::{CandidType, Nat};
+use ic_cdk::{query, update};
+use icrc_ledger_types::{
+    icrc::generic_metadata_value::MetadataValue,
+    icrc1::account::{Account, Subaccount},
+};
+use serde_derive::{Deserialize, Serialize};
+
+// offset certificates are soulbound, they stay with the account that paid for the offset
+const NON_TRANSFERABLE_ERROR_CODE: u64 = 1;
+const BATCH_TOO_LARGE_ERROR_CODE: u64 = 2;
+const DEFAULT_TAKE: usize = 100;
+const MAX_TAKE: usize = 1000;
+const MAX_QUERY_BATCH_SIZE: usize = 100;
+const MAX_UPDATE_BATCH_SIZE: usize = 20;
+const MAX_MEMO_SIZE: usize = 32;
+// transfers are never executed, the windows only tell wallets what the standard asks for
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+
+/// A minted offset certificate, one per settled payment with a vendor proof.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OffsetCertificate {
+    pub owner: Account,
+    pub payment_id: u64,
+    pub block_height: Nat,
+    pub ticket_count: f64,
+    pub kilos_co2: f64,
+    pub vendor: String,
+    pub project: String,
+    pub node_id: Option<String>,
+    pub client: Option<String>,
+    pub proof_url: String,
+    pub minted_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type TransferResult = Result<Nat, TransferError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+pub type TokenMetadata = Vec<(String, MetadataValue)>;
+
+thread_local! {
+    static CERTIFICATES: RefCell<BTreeMap<u64, OffsetCertificate>> = RefCell::default();
+}
+
+/// Mints a certificate and returns its token id. Token ids start at 1.
+pub fn mint_certificate(certificate: OffsetCertificate) -> u64 {
+    CERTIFICATES.with(|c| {
+        let mut certificates = c.borrow_mut();
+        let token_id = certificates.keys().next_back().map_or(1, |id| id + 1);
+        certificates.insert(token_id, certificate);
+        token_id
+    })
+}
+
+pub fn has_certificate(payment_id: u64) -> bool {
+    CERTIFICATES.with(|c| c.borrow().values().any(|certificate| certificate.payment_id == payment_id))
+}
+
+pub fn certificates_snapshot() -> BTreeMap<u64, OffsetCertificate> {
+    CERTIFICATES.with(|c| c.borrow().clone())
+}
+
+pub fn restore_certificates(certificates: BTreeMap<u64, OffsetCertificate>) {
+    CERTIFICATES.with(|c| *c.borrow_mut() = certificates);
+}
+
+fn token_id_to_u64(token_id: &Nat) -> Option<u64> {
+    u64::try_from(&token_id.0).ok()
+}
+
+fn take_or_default(take: Option<Nat>) -> usize {
+    take.map_or(DEFAULT_TAKE, |t| usize::try_from(&t.0).unwrap_or(MAX_TAKE))
+        .min(MAX_TAKE)
+}
+
+// queries cannot return an error, so oversized batches are rejected by trapping
+fn check_query_batch_size(len: usize) {
+    if len > MAX_QUERY_BATCH_SIZE {
+        ic_cdk::trap(&format!("At most {} items can be queried at once", MAX_QUERY_BATCH_SIZE));
+    }
+}
+
+fn token_metadata(token_id: u64, certificate: &OffsetCertificate) -> TokenMetadata {
+    let mut metadata = vec![
+        MetadataValue::entry("icrc7:name", format!("Offset certificate #{}", token_id)),
+        MetadataValue::entry("offset:payment_id", certificate.payment_id),
+        (
+            "offset:block_height".to_string(),
+            MetadataValue::Nat(certificate.block_height.clone()),
+        ),
+        MetadataValue::entry("offset:tickets", certificate.ticket_count.to_string()),
+        MetadataValue::entry("offset:kilos_co2", certificate.kilos_co2.to_string()),
+        MetadataValue::entry("offset:vendor", certificate.vendor.as_str()),
+        MetadataValue::entry("offset:project", certificate.project.as_str()),
+        MetadataValue::entry("offset:proof_url", certificate.proof_url.as_str()),
+        MetadataValue::entry("offset:minted_at", certificate.minted_at),
+    ];
+    if let Some(node_id) = &certificate.node_id {
+        metadata.push(MetadataValue::entry("offset:node_id", node_id.as_str()));
+    }
+    if let Some(client) = &certificate.client {
+        metadata.push(MetadataValue::entry("offset:client", client.as_str()));
+    }
+    metadata
+}
+
+#[query]
+fn icrc7_name() -> String {
+    "IC Footprint Offset Certificates".to_string()
+}
+
+#[query]
+fn icrc7_symbol() -> String {
+    "ICFOC".to_string()
+}
+
+#[query]
+fn icrc7_description() -> Option<String> {
+    Some("Non-transferable certificates for emissions offset through IC Footprint".to_string())
+}
+
+#[query]
+fn icrc7_total_supply() -> Nat {
+    CERTIFICATES.with(|c| Nat::from(c.borrow().len() as u64))
+}
+
+#[query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    None
+}
+
+#[query]
+fn icrc7_logo() -> Option<String> {
+    None
+}
+
+#[query]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_QUERY_BATCH_SIZE as u64))
+}
+
+#[query]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_UPDATE_BATCH_SIZE as u64))
+}
+
+#[query]
+fn icrc7_default_take_value() -> Option<Nat> {
+    Some(Nat::from(DEFAULT_TAKE as u64))
+}
+
+#[query]
+fn icrc7_max_take_value() -> Option<Nat> {
+    Some(Nat::from(MAX_TAKE as u64))
+}
+
+#[query]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(Nat::from(MAX_MEMO_SIZE as u64))
+}
+
+#[query]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[query]
+fn icrc7_tx_window() -> Option<Nat> {
+    Some(Nat::from(TX_WINDOW_NANOS))
+}
+
+#[query]
+fn icrc7_permitted_drift() -> Option<Nat> {
+    Some(Nat::from(PERMITTED_DRIFT_NANOS))
+}
+
+#[query]
+fn icrc7_collection_metadata() -> TokenMetadata {
+    vec![
+        MetadataValue::entry("icrc7:name", icrc7_name()),
+        MetadataValue::entry("icrc7:symbol", icrc7_symbol()),
+        MetadataValue::entry("icrc7:description", icrc7_description().unwrap_or_default()),
+        ("icrc7:total_supply".to_string(), MetadataValue::Nat(icrc7_total_supply())),
+        MetadataValue::entry("icrc7:max_query_batch_size", MAX_QUERY_BATCH_SIZE as u64),
+        MetadataValue::entry("icrc7:max_update_batch_size", MAX_UPDATE_BATCH_SIZE as u64),
+        MetadataValue::entry("icrc7:default_take_value", DEFAULT_TAKE as u64),
+        MetadataValue::entry("icrc7:max_take_value", MAX_TAKE as u64),
+        MetadataValue::entry("icrc7:max_memo_size", MAX_MEMO_SIZE as u64),
+        MetadataValue::entry("icrc7:tx_window", TX_WINDOW_NANOS),
+        MetadataValue::entry("icrc7:permitted_drift", PERMITTED_DRIFT_NANOS),
+    ]
+}
+
+#[query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<TokenMetadata>> {
+    check_query_batch_size(token_ids.len());
+    CERTIFICATES.with(|c| {
+        let certificates = c.borrow();
+        token_ids
+            .iter()
+            .map(|token_id| {
+                let token_id = token_id_to_u64(token_id)?;
+                certificates
+                    .get(&token_id)
+                    .map(|certificate| token_metadata(token_id, certificate))
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    check_query_batch_size(token_ids.len());
+    CERTIFICATES.with(|c| {
+        let certificates = c.borrow();
+        token_ids
+            .iter()
+            .map(|token_id| {
+                token_id_to_u64(token_id)
+                    .and_then(|id| certificates.get(&id))
+                    .map(|certificate| certificate.owner)
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    check_query_batch_size(accounts.len());
+    CERTIFICATES.with(|c| {
+        let certificates = c.borrow();
+        accounts
+            .iter()
+            .map(|account| {
+                let count = certificates
+                    .values()
+                    .filter(|certificate| &certificate.owner == account)
+                    .count();
+                Nat::from(count as u64)
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = prev.as_ref().and_then(token_id_to_u64).map_or(0, |id| id + 1);
+    CERTIFICATES.with(|c| {
+        c.borrow()
+            .range(start..)
+            .take(take_or_default(take))
+            .map(|(id, _)| Nat::from(*id))
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = prev.as_ref().and_then(token_id_to_u64).map_or(0, |id| id + 1);
+    CERTIFICATES.with(|c| {
+        c.borrow()
+            .range(start..)
+            .filter(|(_, certificate)| certificate.owner == account)
+            .take(take_or_default(take))
+            .map(|(id, _)| Nat::from(*id))
+            .collect()
+    })
+}
+
+// every transfer is rejected, the certificate is proof of the owner's own offset
+#[update]
+fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: Nat::from(BATCH_TOO_LARGE_ERROR_CODE),
+            message: format!("At most {} transfers can be made at once", MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+    CERTIFICATES.with(|c| {
+        let certificates = c.borrow();
+        args.iter()
+            .map(|arg| {
+                let exists = token_id_to_u64(&arg.token_id)
+                    .is_some_and(|id| certificates.contains_key(&id));
+                if !exists {
+                    return Some(Err(TransferError::NonExistingTokenId));
+                }
+                Some(Err(TransferError::GenericError {
+                    error_code: Nat::from(NON_TRANSFERABLE_ERROR_CODE),
+                    message: "Offset certificates are non-transferable".to_string(),
+                }))
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-7".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-10".to_string(),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_is_clamped_to_the_max_take_value() {
+        assert_eq!(take_or_default(None), DEFAULT_TAKE);
+        assert_eq!(take_or_default(Some(Nat::from(5u64))), 5);
+        assert_eq!(take_or_default(Some(Nat::from(1_000_000u64))), MAX_TAKE);
+        assert_eq!(take_or_default(Some(Nat::from(u128::MAX))), MAX_TAKE);
+    }
+}
@@ -0,0 +1,75 @@
+use std::cell::RefCell;
+
+use candid::CandidType;
+use ic_cdk::api::{data_certificate, set_certified_data};
+use ic_certified_map::{labeled, labeled_hash, leaf_hash, AsHashTree, Hash, HashTree, RbTree};
+use serde::Serialize;
+
+// label under which the payments tree is certified
+const PAYMENTS_LABEL: &[u8] = b"payments";
+
+thread_local! {
+    static PAYMENT_TREE: RefCell<RbTree<Vec<u8>, Hash>> = const { RefCell::new(RbTree::new()) };
+}
+
+/// Hash of the candid encoding of a value, which is what the frontend recomputes
+/// to check a returned record against the witness.
+fn value_hash<T: CandidType>(value: &T) -> Hash {
+    let bytes = candid::encode_one(value).expect("Failed to encode value for certification");
+    leaf_hash(&bytes)
+}
+
+fn payment_key(payment_id: u64) -> Vec<u8> {
+    payment_id.to_be_bytes().to_vec()
+}
+
+fn update_certified_data() {
+    PAYMENT_TREE.with(|tree| {
+        set_certified_data(&labeled_hash(PAYMENTS_LABEL, &tree.borrow().root_hash()));
+    });
+}
+
+/// Adds (or replaces) a payment in the certified tree and refreshes the certified data.
+pub fn certify_payment<T: CandidType>(payment_id: u64, payment: &T) {
+    PAYMENT_TREE.with(|tree| {
+        tree.borrow_mut()
+            .insert(payment_key(payment_id), value_hash(payment));
+    });
+    update_certified_data();
+}
+
+/// Removes a payment from the certified tree and refreshes the certified data.
+pub fn uncertify_payment(payment_id: u64) {
+    PAYMENT_TREE.with(|tree| tree.borrow_mut().delete(&payment_key(payment_id)));
+    update_certified_data();
+}
+
+/// Rebuilds the whole tree, used after an upgrade when the heap state is restored.
+pub fn certify_all<'a, T: CandidType + 'a>(payments: impl Iterator<Item = (&'a u64, &'a T)>) {
+    PAYMENT_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        *tree = RbTree::new();
+        for (payment_id, payment) in payments {
+            tree.insert(payment_key(*payment_id), value_hash(payment));
+        }
+    });
+    update_certified_data();
+}
+
+fn encode_witness(tree: HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    tree.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
+
+/// Returns the data certificate together with a CBOR encoded witness covering every payment.
+///
+/// The certificate is only available in non-replicated query calls, in every other
+/// context an empty vector is returned.
+pub fn payments_witness() -> (Vec<u8>, Vec<u8>) {
+    let certificate = data_certificate().unwrap_or_default();
+    let witness = PAYMENT_TREE
+        .with(|tree| encode_witness(labeled(PAYMENTS_LABEL, tree.borrow().as_hash_tree())));
+    (certificate, witness)
+}
@@ -0,0 +1,324 @@
+use std::{cell::RefCell, collections::BTreeMap, time::Duration};
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::{api::call, caller, query, update};
+use icrc_ledger_types::icrc1::{
+    account::{Account, Subaccount},
+    transfer::{TransferArg, TransferError},
+};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cawa_poster::caller_may_contribute;
+use crate::esg_wallet::{get_price, get_ticket_price, ledger_canister, settle_payment, treasury_account};
+
+// how often the deposit subaccounts are checked for incoming transfers
+const DEPOSIT_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const DEPOSIT_DOMAIN: &[u8] = b"icfootprint-deposit";
+// number of sweeps kept per deposit account, older ones are dropped
+const MAX_SWEEP_HISTORY: usize = 100;
+// every open account costs a balance lookup per check, so a payer can only open a few
+const MAX_ACCOUNTS_PER_PAYER: usize = 10;
+const MAX_TICKET_COUNT: u64 = 1000000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DepositSweep {
+    pub swept_at: u64,
+    pub amount: Nat,
+    pub ticket_count: u64,
+    pub block_height: Option<Nat>,
+    pub payment_id: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DepositAccount {
+    pub payer: Principal,
+    pub node_id: Option<String>,
+    pub account: Account,
+    pub opened_at: u64,
+    pub sweeps: Vec<DepositSweep>,
+}
+
+thread_local! {
+    // keyed by the deposit subaccount
+    static DEPOSIT_ACCOUNTS: RefCell<BTreeMap<Subaccount, DepositAccount>> = RefCell::default();
+    static SWEEP_IN_PROGRESS: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// Sweep run in progress, the flag is cleared when it is dropped. That also happens when a ledger
+// callback traps, so a trap does not stop the sweeps for good.
+struct Sweep;
+
+impl Sweep {
+    fn start() -> Option<Sweep> {
+        (!SWEEP_IN_PROGRESS.with(|s| s.replace(true))).then_some(Sweep)
+    }
+}
+
+impl Drop for Sweep {
+    fn drop(&mut self) {
+        SWEEP_IN_PROGRESS.with(|s| s.replace(false));
+    }
+}
+
+pub fn deposit_accounts_snapshot() -> BTreeMap<Subaccount, DepositAccount> {
+    DEPOSIT_ACCOUNTS.with(|d| d.borrow().clone())
+}
+
+pub fn restore_deposit_accounts(deposit_accounts: BTreeMap<Subaccount, DepositAccount>) {
+    DEPOSIT_ACCOUNTS.with(|d| *d.borrow_mut() = deposit_accounts);
+}
+
+/// Starts the periodic deposit check, has to be called from init and post_upgrade.
+pub fn start_deposit_timer() {
+    ic_cdk_timers::set_timer_interval(DEPOSIT_CHECK_INTERVAL, || {
+        ic_cdk::spawn(sweep_deposits())
+    });
+}
+
+/// Deterministic subaccount of a payer, optionally scoped to the node the deposits offset.
+fn deposit_subaccount(payer: &Principal, node_id: &Option<String>) -> Subaccount {
+    let mut hasher = Sha256::new();
+    hasher.update(DEPOSIT_DOMAIN);
+    hasher.update([payer.as_slice().len() as u8]);
+    hasher.update(payer.as_slice());
+    if let Some(node_id) = node_id {
+        hasher.update(node_id.as_bytes());
+    }
+    hasher.finalize().into()
+}
+
+fn deposit_account(payer: &Principal, node_id: &Option<String>) -> Account {
+    Account {
+        owner: ic_cdk::api::id(),
+        subaccount: Some(deposit_subaccount(payer, node_id)),
+    }
+}
+
+async fn balance_of(ledger: Principal, account: Account) -> Result<Nat, String> {
+    call::call::<(Account,), (Nat,)>(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map(|(balance,)| balance)
+        .map_err(|(code, message)| format!("icrc1_balance_of failed: {:?} {}", code, message))
+}
+
+async fn ledger_fee(ledger: Principal) -> Result<Nat, String> {
+    call::call::<(), (Nat,)>(ledger, "icrc1_fee", ())
+        .await
+        .map(|(fee,)| fee)
+        .map_err(|(code, message)| format!("icrc1_fee failed: {:?} {}", code, message))
+}
+
+async fn sweep_to_treasury(ledger: Principal, subaccount: Subaccount, amount: Nat) -> Result<Nat, String> {
+    let transfer_args = TransferArg {
+        from_subaccount: Some(subaccount),
+        to: treasury_account(),
+        fee: None,
+        created_at_time: None,
+        memo: None,
+        amount,
+    };
+
+    match call::call::<(TransferArg,), (Result<Nat, TransferError>,)>(
+        ledger,
+        "icrc1_transfer",
+        (transfer_args,),
+    )
+    .await
+    {
+        Ok((Ok(block_height),)) => Ok(block_height),
+        Ok((Err(e),)) => Err(format!("Sweep transfer failed: {:?}", e)),
+        Err((code, message)) => Err(format!("Sweep transfer failed: {:?} {}", code, message)),
+    }
+}
+
+// Turns the balance of every deposit subaccount into whole tickets: the tickets are swept to the
+// treasury and settled as a normal payment, any remainder stays for the next deposit.
+async fn sweep_deposits() {
+    let Some(_sweep) = Sweep::start() else {
+        return;
+    };
+
+    let ledger = match ledger_canister() {
+        Ok(ledger) => ledger,
+        Err(e) => {
+            ic_cdk::println!("Cannot sweep deposits, invalid ledger canister id: {}", e);
+            return;
+        }
+    };
+    let fee = match ledger_fee(ledger).await {
+        Ok(fee) => fee,
+        Err(e) => {
+            ic_cdk::println!("Cannot sweep deposits: {}", e);
+            return;
+        }
+    };
+
+    let accounts: Vec<DepositAccount> = DEPOSIT_ACCOUNTS.with(|d| d.borrow().values().cloned().collect());
+    for deposit in accounts {
+        if let Some(sweep) = sweep_deposit(ledger, &fee, &deposit).await {
+            let subaccount = deposit.account.subaccount.unwrap_or_default();
+            DEPOSIT_ACCOUNTS.with(|d| {
+                if let Some(stored) = d.borrow_mut().get_mut(&subaccount) {
+                    stored.sweeps.push(sweep);
+                    let excess = stored.sweeps.len().saturating_sub(MAX_SWEEP_HISTORY);
+                    stored.sweeps.drain(..excess);
+                }
+            });
+        }
+    }
+}
+
+async fn sweep_deposit(ledger: Principal, fee: &Nat, deposit: &DepositAccount) -> Option<DepositSweep> {
+    let balance = match balance_of(ledger, deposit.account).await {
+        Ok(balance) => balance,
+        Err(e) => {
+            ic_cdk::println!("Cannot read deposit balance of {}: {}", deposit.account, e);
+            return None;
+        }
+    };
+
+    let ticket_price = get_ticket_price();
+    if ticket_price <= 0.0 || balance <= *fee {
+        return None;
+    }
+    // a balance beyond u64 cannot be priced or transferred in one go, it is left for a manual refund
+    let Ok(spendable) = u64::try_from(&(balance - fee.clone()).0) else {
+        ic_cdk::println!("Skipping deposit account {}, its balance does not fit in u64", deposit.account);
+        return None;
+    };
+    // larger deposits are swept in several runs, like other purchases are capped per call
+    let ticket_count = ((spendable as f64 / ticket_price).floor() as u64).min(MAX_TICKET_COUNT);
+    if ticket_count == 0 {
+        return None;
+    }
+
+    let amount = Nat::from(get_price(ticket_count as f64) as u64);
+    let mut sweep = DepositSweep {
+        swept_at: ic_cdk::api::time(),
+        amount: amount.clone(),
+        ticket_count,
+        block_height: None,
+        payment_id: None,
+        error: None,
+    };
+
+    let subaccount = deposit.account.subaccount.unwrap_or_default();
+    match sweep_to_treasury(ledger, subaccount, amount).await {
+        Ok(block_height) => {
+            let (payment_id, _) = settle_payment(
+                deposit.payer,
+                block_height.clone(),
+                ticket_count,
+                deposit.node_id.clone(),
+            )
+            .await;
+            sweep.block_height = Some(block_height);
+            sweep.payment_id = Some(payment_id);
+        }
+        Err(e) => sweep.error = Some(e),
+    }
+    Some(sweep)
+}
+
+// deposit account of the caller for push-style payments, it only derives the address: transfers
+// to it are not picked up before the account is opened with openDepositAccount
+#[query(name = "getDepositAccount")]
+fn get_deposit_account(node_id: Option<String>) -> Account {
+    deposit_account(&caller(), &node_id)
+}
+
+fn validate_node_id(node_id: &Option<String>) -> Result<(), String> {
+    match node_id {
+        Some(node_id) if Principal::from_text(node_id).is_err() => Err(format!("Invalid node id {}", node_id)),
+        _ => Ok(()),
+    }
+}
+
+// registers the deposit account of the caller so incoming transfers are turned into payments
+#[update(name = "openDepositAccount")]
+fn open_deposit_account(node_id: Option<String>) -> Result<Account, String> {
+    let payer = caller();
+    if payer == Principal::anonymous() || !caller_may_contribute() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    validate_node_id(&node_id)?;
+
+    let subaccount = deposit_subaccount(&payer, &node_id);
+    let account = deposit_account(&payer, &node_id);
+    DEPOSIT_ACCOUNTS.with(|d| {
+        let mut deposit_accounts = d.borrow_mut();
+        if deposit_accounts.contains_key(&subaccount) {
+            return Ok(account);
+        }
+        let opened = deposit_accounts.values().filter(|deposit| deposit.payer == payer).count();
+        if opened >= MAX_ACCOUNTS_PER_PAYER {
+            return Err(format!("At most {} deposit accounts can be opened", MAX_ACCOUNTS_PER_PAYER));
+        }
+        deposit_accounts.insert(
+            subaccount,
+            DepositAccount {
+                payer,
+                node_id,
+                account,
+                opened_at: ic_cdk::api::time(),
+                sweeps: vec![],
+            },
+        );
+        Ok(account)
+    })
+}
+
+// deposit accounts opened by the caller, including the history of detected deposits
+#[query(name = "getDepositAccounts")]
+fn get_deposit_accounts() -> Vec<DepositAccount> {
+    let caller = caller();
+    DEPOSIT_ACCOUNTS.with(|d| {
+        d.borrow()
+            .values()
+            .filter(|deposit| deposit.payer == caller)
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE: &str = "eq6en-6jqla-fbu5s-daskr-h6hx2-376n5-iqabl-qgrng-gfqmv-n3yjr-mqe";
+
+    #[test]
+    fn subaccounts_are_stable_per_payer_and_node() {
+        let payer = Principal::from_slice(&[1]);
+        let node_id = Some(NODE.to_string());
+        assert_eq!(deposit_subaccount(&payer, &node_id), deposit_subaccount(&payer, &node_id));
+        assert_eq!(deposit_subaccount(&payer, &None), deposit_subaccount(&payer, &None));
+    }
+
+    #[test]
+    fn subaccounts_differ_per_payer_and_node() {
+        let payer = Principal::from_slice(&[1]);
+        let other_payer = Principal::from_slice(&[2]);
+        let node_id = Some(NODE.to_string());
+        let subaccounts = [
+            deposit_subaccount(&payer, &None),
+            deposit_subaccount(&payer, &node_id),
+            deposit_subaccount(&other_payer, &None),
+            deposit_subaccount(&other_payer, &node_id),
+        ];
+        for (i, a) in subaccounts.iter().enumerate() {
+            for b in &subaccounts[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn only_principal_node_ids_are_accepted() {
+        assert!(validate_node_id(&None).is_ok());
+        assert!(validate_node_id(&Some(NODE.to_string())).is_ok());
+        assert!(validate_node_id(&Some("not a node".to_string())).is_err());
+    }
+}
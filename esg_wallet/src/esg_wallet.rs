@@ -1,6 +1,7 @@
 use std::{
     cell::{Cell, RefCell},
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
 };
 
 use candid::{CandidType, Nat, Principal};
@@ -12,33 +13,94 @@ use ic_cdk::{
     api::call, caller, export_candid, id, init, post_upgrade, pre_upgrade, query, storage, update,
 };
 use icrc_ledger_types::{
-    icrc1::account::Account,
+    icrc1::account::{Account, Subaccount},
     icrc2::transfer_from::{TransferFromArgs, TransferFromError},
 };
 use serde_derive::{Deserialize, Serialize};
-use crate::cawa_poster::send;
+use crate::cawa_poster::{caller_may_contribute, post_contribution, ticket_co2e};
 use crate::cawa_poster::get_contribution_by_id;
+use crate::cawa_poster::{CAWA_PROJECT_ID, CAWA_VENDOR};
+use crate::certificate_nft::{
+    certificates_snapshot, has_certificate, mint_certificate, restore_certificates, OffsetCertificate,
+    SupportedStandard, TokenMetadata, TransferArg, TransferResult,
+};
+use crate::certification::{certify_all, certify_payment, payments_witness, uncertify_payment};
+use crate::batch_payments::{
+    batch_payments_snapshot, restore_batch_payments, BatchAllocation, BatchPayment,
+};
+use crate::deposits::{
+    deposit_accounts_snapshot, restore_deposit_accounts, start_deposit_timer, DepositAccount,
+};
+use crate::subscriptions::{
+    restore_subscriptions, start_subscription_timer, subscriptions_snapshot, Subscription,
+};
+use crate::notifications::{
+    enqueue, node_manager_canister_id, queue_snapshot, restore_queue,
+    set_node_manager_canister_id, start_notification_timer, PendingNotification,
+};
+use crate::units::{Co2e, Co2eUnit};
 use std::collections::HashSet;
 use lazy_static::lazy_static;
 use serde_json::json;
 use serde_json::Value;
 
 type PaymentStore = BTreeMap<u64, Payment>;
+// layout of the stable memory, the optional parts were added in later upgrades
+type StableState = (
+    BTreeMap<u64, Payment>,
+    String,
+    f64,
+    u64,
+    String,
+    Option<BTreeMap<u64, OffsetCertificate>>,
+    Option<BTreeMap<u64, Subscription>>,
+    Option<BTreeMap<Subaccount, DepositAccount>>,
+    Option<BTreeMap<u64, BatchPayment>>,
+    Option<BTreeSet<u64>>,
+    Option<Principal>,
+    Option<BTreeMap<u64, PendingNotification>>,
+);
+
+const TREASURY_PRINCIPAL: &str = "p7fau-co6y6-lqstu-3i3z3-ujquv-bu7a2-ngent-b2j62-a3ctd-2uprh-tae";
+// how often the proofs of contributions Cawa had not published yet are looked up again, and how
+// many of them per run
+const PROOF_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_PROOF_LOOKUPS: usize = 20;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Conf {
     ledger_canister_id: Principal,
+    // node_manager that is notified of settled payments
+    node_manager_canister_id: Option<Principal>,
     // ticket_price: f64,
 }
 
 #[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
-struct Payment {
+pub(crate) struct Payment {
     pub block_height: Nat,
     pub payer: String,
     pub ticket_count: f64,
     pub ticket_price: f64,
     pub node_id: Option<String>,
     pub cawa_url: String,
+    pub client: Option<String>,
+    pub batch_id: Option<u64>,
+    // CO2e bought with the payment, None for payments recorded before it was stored
+    pub co2e: Option<Co2e>,
+    // project and vendor the CO2e was bought from, None for payments recorded before they were
+    // stored
+    pub project: Option<String>,
+    pub vendor: Option<String>,
+    // Cawa contribution of the payment, None when none was made or it was recorded before the
+    // id was stored
+    pub contribution_id: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct CertifiedPayments {
+    pub payments: Vec<(u64, Payment)>,
+    pub certificate: Vec<u8>,
+    pub witness: Vec<u8>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, Ord, PartialEq, Eq, PartialOrd)]
@@ -85,6 +147,24 @@ lazy_static! {
     };
 }
 
+// name of the client a payment is attributed to, mirrors the client sent to Cawa
+pub(crate) fn attributed_client(node_id: &Option<String>) -> String {
+    match node_id {
+        Some(node_id)
+            if node_id != "eq6en-6jqla-fbu5s-daskr-h6hx2-376n5-iqabl-qgrng-gfqmv-n3yjr-mqe"
+                && !CLIENT.node_ids.contains(node_id) =>
+        {
+            "nodes".to_string()
+        }
+        _ => CLIENT.name.clone(),
+    }
+}
+
+// a proof url is only stored when Cawa returned one, otherwise it holds an error message
+fn has_proof(cawa_url: &str) -> bool {
+    cawa_url.starts_with("http")
+}
+
 thread_local! {
     static PAYMENT_STORE: RefCell<PaymentStore> = RefCell::default();
     static TICKET_PRICE: Cell<f64> = Cell::new(1.0);
@@ -92,21 +172,30 @@ thread_local! {
     static CURRENT_PAYMENT_ID: Cell<u64> = Cell::new(0);
     static CLIENT_STORE: RefCell<BTreeMap<String, Client>> = RefCell::default();
     static AUTHORIZED_PRINCIPALS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+    // payments the offset ledger of the node_manager already recorded, they are not sent again
+    static OFFSET_RECORDED: RefCell<BTreeSet<u64>> = RefCell::default();
+    // last payment whose proof was looked up again, the next run continues after it
+    static PROOF_CURSOR: Cell<u64> = const { Cell::new(0) };
 }
 
 #[init]
 fn init(conf: Conf) {
     // TICKET_PRICE.set(conf.ticket_price);
     LEDGER_CANISTER_ID.set(conf.ledger_canister_id.to_string());
+    set_node_manager_canister_id(conf.node_manager_canister_id);
+    start_subscription_timer();
+    start_deposit_timer();
+    start_notification_timer();
+    start_proof_timer();
 }
 
 #[query(name = "getTicketPrice")]
-fn get_ticket_price() -> f64 {
+pub(crate) fn get_ticket_price() -> f64 {
     return TICKET_PRICE.get();
 }
 
 #[query(name = "getPrice")]
-fn get_price(ticket_count: f64) -> f64 {
+pub(crate) fn get_price(ticket_count: f64) -> f64 {
     return f64::from(ticket_count * TICKET_PRICE.get());
 }
 
@@ -115,14 +204,30 @@ fn get_purchases() -> Vec<Payment> {
     return PAYMENT_STORE.take().values().cloned().collect();
 }
 
+// same as getPurchases, plus a certificate and witness so the result can be verified
+#[query(name = "getPurchasesCertified")]
+fn get_purchases_certified() -> CertifiedPayments {
+    let payments = PAYMENT_STORE.with(|store| {
+        store
+            .borrow()
+            .iter()
+            .map(|(id, payment)| (*id, payment.clone()))
+            .collect()
+    });
+    let (certificate, witness) = payments_witness();
+    CertifiedPayments {
+        payments,
+        certificate,
+        witness,
+    }
+}
+
 #[update(name = "registerPayment")]
 async fn register_payment(ticket_count: u64, nodeId: Option<String>) -> String {
     let max_ticket_count = 1000000;
     let total_price = get_price(ticket_count as f64);
-    let ledger_canister_id = LEDGER_CANISTER_ID.with(|id| id.borrow().clone());
-    let client1 = CLIENT.name.clone();
 
-    if ticket_count <= 0 {
+    if ticket_count == 0 {
         return serde_json::to_string(&json!({"error": "Invalid ticket count"})).unwrap();
     }
 
@@ -130,94 +235,222 @@ async fn register_payment(ticket_count: u64, nodeId: Option<String>) -> String {
         return serde_json::to_string(&json!({"error": "Ticket count is too big"})).unwrap();
     }
 
-    match Principal::from_text(ledger_canister_id) {
-        Ok(principal) => {
-            let transfer_args = TransferFromArgs {
-                spender_subaccount: None,
-                from: Account {
-                    owner: caller(),
-                    subaccount: None,
-                },
-                to: Account {
-                    owner: Principal::from_text("p7fau-co6y6-lqstu-3i3z3-ujquv-bu7a2-ngent-b2j62-a3ctd-2uprh-tae").unwrap(),
-                    subaccount: None,
-                },
-                amount: Nat::from(total_price as u64),
-                fee: None,
-                memo: None,
-                created_at_time: None,
-            };
+    // the contribution used to be sent with `send`, which refused callers that are not authorized
+    if !caller_may_contribute() {
+        return serde_json::to_string(&json!({"error": "Unauthorized: the caller is not allowed to perform this action."})).unwrap();
+    }
 
-            let transfer_result = call::call::<
-                (TransferFromArgs,),
-                (Result<Nat, TransferFromError>,),
-            >(principal, "icrc2_transfer_from", (transfer_args,))
-            .await;
+    let payer = caller();
+    let from = Account {
+        owner: payer,
+        subaccount: None,
+    };
 
-            if let Err(error) = transfer_result {
-                ic_cdk::println!("Transfer error {:?} and message {}", error.0, error.1);
-                return serde_json::to_string(&json!({"error": "Transaction Error"})).unwrap();
-            } else if let Ok((transactions_response,)) = transfer_result {
-                match transactions_response {
-                    Ok(block_height) => {
-                        CURRENT_PAYMENT_ID.set(CURRENT_PAYMENT_ID.get() + 1);
-
-                       // Define contribution_id before the conditional logic
-                       let mut contribution_id = String::new();
-
-                        if let Some(ref node_id) = nodeId {
-                            // check if the node_id is a specified string
-                            if node_id == "eq6en-6jqla-fbu5s-daskr-h6hx2-376n5-iqabl-qgrng-gfqmv-n3yjr-mqe" {
-                                // set client to Openchat
-                                let client1 = CLIENT.name.clone();
-                            }
-                            else {
-                                // set up a new client for nodes that are not Openchat
-                                // check if the node_id is not in the list of node_ids for client Openchat
-                                if !CLIENT.node_ids.contains(&node_id) {
-                                // if not create a new client for these nodes
-                                let client1 = Client {
-                                    name: "nodes".to_string(),
-                                    node_ids: vec![node_id.to_string()],
-                                };
-                                contribution_id = send(client1.name.clone(), ticket_count as f64).await;
-                            }
-                        }
-                        } else {
-                            contribution_id = send(client1, ticket_count as f64).await;
-                        }
-
-                        let cawa_url = get_proof(contribution_id.clone()).await;
-                        let payment = Payment {
-                            block_height,
-                            ticket_count: ticket_count as f64,
-                            payer: caller().to_string(),
-                            ticket_price: TICKET_PRICE.get(), 
-                            node_id: nodeId.clone(),
-                            cawa_url: cawa_url,
-                        };
-
-                        PAYMENT_STORE.with(|store| {
-                            store.borrow_mut().insert(
-                                CURRENT_PAYMENT_ID.get(),
-                                payment.clone(),
-                            )
-                        });
-
-                        // let _ = set_offset_emissions(nodeId).await;
-
-                        // Return the payment struct as a JSON string
-                        return serde_json::to_string(&payment).unwrap();
-                    },
-                    Err(e) => {
-                        return serde_json::to_string(&json!({"error": format!("The http_request resulted into error. Error: {:?}", e)})).unwrap();
-                    }
-                }
-            } else {
-                return serde_json::to_string(&json!({"error": "Unknown error"})).unwrap();
-            }
+    match transfer_from_payer(from, total_price as u64).await {
+        Ok(block_height) => {
+            let (_, payment) = settle_payment(payer, block_height, ticket_count, nodeId).await;
+
+            // let _ = set_offset_emissions(nodeId).await;
+
+            // Return the payment struct as a JSON string
+            serde_json::to_string(&payment).unwrap()
+        }
+        Err(error) => serde_json::to_string(&json!({"error": error})).unwrap(),
+    }
+}
+
+pub(crate) fn ledger_canister() -> Result<Principal, String> {
+    let ledger_canister_id = LEDGER_CANISTER_ID.with(|id| id.borrow().clone());
+    Principal::from_text(ledger_canister_id).map_err(|err| err.to_string())
+}
+
+// account that receives the funds of every purchase
+pub(crate) fn treasury_account() -> Account {
+    Account {
+        owner: Principal::from_text(TREASURY_PRINCIPAL).unwrap(),
+        subaccount: None,
+    }
+}
+
+// moves funds the payer approved for this canister into the treasury account,
+// returns the block height of the ledger transfer
+pub(crate) async fn transfer_from_payer(from: Account, amount: u64) -> Result<Nat, String> {
+    let principal = ledger_canister()?;
+
+    let transfer_args = TransferFromArgs {
+        spender_subaccount: None,
+        from,
+        to: treasury_account(),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let transfer_result = call::call::<
+        (TransferFromArgs,),
+        (Result<Nat, TransferFromError>,),
+    >(principal, "icrc2_transfer_from", (transfer_args,))
+    .await;
+
+    match transfer_result {
+        Ok((Ok(block_height),)) => Ok(block_height),
+        Ok((Err(e),)) => Err(format!("The http_request resulted into error. Error: {:?}", e)),
+        Err(error) => {
+            ic_cdk::println!("Transfer error {:?} and message {}", error.0, error.1);
+            Err("Transaction Error".to_string())
+        }
+    }
+}
+
+// settlement pipeline for a transfer that already landed on the ledger: contribution to Cawa,
+// proof lookup, payment record and offset certificate. Returns the payment id and the payment.
+pub(crate) async fn settle_payment(
+    payer: Principal,
+    block_height: Nat,
+    ticket_count: u64,
+    node_id: Option<String>,
+) -> (u64, Payment) {
+    let contribution_id = contribute(ticket_count, &node_id).await;
+    let client = attributed_client(&node_id);
+    record_payment(payer, block_height, ticket_count, node_id, client, contribution_id, None).await
+}
+
+// sends the contribution for a purchase to Cawa and returns the contribution id
+pub(crate) async fn contribute(ticket_count: u64, node_id: &Option<String>) -> Result<String, String> {
+    // the Openchat nodes (eq6en and the ones listed for the client) are covered by the Openchat
+    // contribution, other nodes get their own one on behalf of the "nodes" client
+    let client = attributed_client(node_id);
+    if node_id.is_some() && client == CLIENT.name {
+        return Ok(String::new());
+    }
+    post_contribution(client, ticket_co2e(ticket_count)).await
+}
+
+// looks up the proof of a contribution, then stores and certifies the payment and mints
+// its offset certificate
+pub(crate) async fn record_payment(
+    payer: Principal,
+    block_height: Nat,
+    ticket_count: u64,
+    node_id: Option<String>,
+    client: String,
+    contribution_id: Result<String, String>,
+    batch_id: Option<u64>,
+) -> (u64, Payment) {
+    CURRENT_PAYMENT_ID.set(CURRENT_PAYMENT_ID.get() + 1);
+    let payment_id = CURRENT_PAYMENT_ID.get();
+
+    // a failed contribution leaves the payment without a proof
+    let contribution_id = match contribution_id {
+        Ok(contribution_id) => Some(contribution_id).filter(|id| !id.is_empty()),
+        Err(e) => {
+            ic_cdk::println!("Contribution for payment {} failed: {}", payment_id, e);
+            None
+        }
+    };
+    let cawa_url = match &contribution_id {
+        Some(contribution_id) => get_proof(contribution_id.clone()).await,
+        None => String::new(),
+    };
+    let payment = Payment {
+        block_height,
+        ticket_count: ticket_count as f64,
+        payer: payer.to_string(),
+        ticket_price: TICKET_PRICE.get(),
+        node_id: node_id.clone(),
+        cawa_url,
+        client: Some(client),
+        batch_id,
+        co2e: Some(ticket_co2e(ticket_count)),
+        project: Some(CAWA_PROJECT_ID.to_string()),
+        vendor: Some(CAWA_VENDOR.to_string()),
+        contribution_id,
+    };
+
+    PAYMENT_STORE.with(|store| store.borrow_mut().insert(payment_id, payment.clone()));
+    certify_payment(payment_id, &payment);
+    enqueue(payment_id);
+    mint_payment_certificate(payment_id, &payment);
+
+    (payment_id, payment)
+}
+
+// Mints the offset certificate of a payment once the purchase is backed by a vendor proof, at
+// most one per payment.
+fn mint_payment_certificate(payment_id: u64, payment: &Payment) {
+    if !has_proof(&payment.cawa_url) || has_certificate(payment_id) {
+        return;
+    }
+    let Ok(owner) = Principal::from_text(&payment.payer) else {
+        return;
+    };
+    mint_certificate(OffsetCertificate {
+        owner: Account {
+            owner,
+            subaccount: None,
+        },
+        payment_id,
+        block_height: payment.block_height.clone(),
+        ticket_count: payment.ticket_count,
+        kilos_co2: payment
+            .co2e
+            .unwrap_or_else(|| ticket_co2e(payment.ticket_count as u64))
+            .in_unit(Co2eUnit::Kilograms),
+        vendor: payment.vendor.clone().unwrap_or_else(|| CAWA_VENDOR.to_string()),
+        project: payment.project.clone().unwrap_or_else(|| CAWA_PROJECT_ID.to_string()),
+        node_id: payment.node_id.clone(),
+        client: payment.client.clone(),
+        proof_url: payment.cawa_url.clone(),
+        minted_at: ic_cdk::api::time(),
+    });
+}
+
+// Looks up the proofs every PROOF_RETRY_INTERVAL, has to be called from init and post_upgrade.
+fn start_proof_timer() {
+    ic_cdk_timers::set_timer_interval(PROOF_RETRY_INTERVAL, || {
+        ic_cdk::spawn(retry_missing_proofs())
+    });
+}
+
+// Looks up the proofs of contributions Cawa had not published when their payment was recorded,
+// MAX_PROOF_LOOKUPS per run in payment order, and mints the certificates of the payments that
+// have one now.
+async fn retry_missing_proofs() {
+    let cursor = PROOF_CURSOR.get();
+    let missing: Vec<(u64, String)> = PAYMENT_STORE.with(|store| {
+        let store = store.borrow();
+        let missing = |(id, payment): (&u64, &Payment)| match &payment.contribution_id {
+            Some(contribution_id) if !has_proof(&payment.cawa_url) => Some((*id, contribution_id.clone())),
+            _ => None,
+        };
+        // continue after the last payment of the previous run, then start over from the first
+        store
+            .range(cursor + 1..)
+            .chain(store.range(..=cursor))
+            .filter_map(missing)
+            .take(MAX_PROOF_LOOKUPS)
+            .collect()
+    });
+    if let Some((last, _)) = missing.last() {
+        PROOF_CURSOR.set(*last);
+    }
+
+    for (payment_id, contribution_id) in missing {
+        let proof_url = get_proof(contribution_id).await;
+        if !has_proof(&proof_url) {
+            continue;
+        }
+        let payment = PAYMENT_STORE.with(|store| {
+            let mut store = store.borrow_mut();
+            let payment = store.get_mut(&payment_id)?;
+            payment.cawa_url = proof_url;
+            Some(payment.clone())
+        });
+        if let Some(payment) = payment {
+            certify_payment(payment_id, &payment);
+            mint_payment_certificate(payment_id, &payment);
         }
-        Err(err) => return serde_json::to_string(&json!({"error": err.to_string()})).unwrap(),
     }
 }
 
@@ -230,6 +463,13 @@ fn pre_upgrade() {
             TICKET_PRICE.get(),
             CURRENT_PAYMENT_ID.get(),
             CLIENT.name.clone(),
+            Some(certificates_snapshot()),
+            Some(subscriptions_snapshot()),
+            Some(deposit_accounts_snapshot()),
+            Some(batch_payments_snapshot()),
+            Some(OFFSET_RECORDED.with(|r| r.borrow().clone())),
+            node_manager_canister_id(),
+            Some(queue_snapshot()),
         ))
         .unwrap()
     })
@@ -237,17 +477,36 @@ fn pre_upgrade() {
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (old_payments, ledger_canister_id, ticket_price, current_payment_id, client): (
-        BTreeMap<u64, Payment>,
-        String,
-        f64,
-        u64,
-        String,
-    ) = storage::stable_restore().unwrap();
+    let (
+        old_payments,
+        ledger_canister_id,
+        ticket_price,
+        current_payment_id,
+        _client,
+        certificates,
+        subscriptions,
+        deposit_accounts,
+        batch_payments,
+        offset_recorded,
+        node_manager,
+        notifications,
+    ): StableState = storage::stable_restore().unwrap();
+    certify_all(old_payments.iter());
     PAYMENT_STORE.with(|payments| *payments.borrow_mut() = old_payments);
     TICKET_PRICE.set(ticket_price);
     LEDGER_CANISTER_ID.set(ledger_canister_id);
     CURRENT_PAYMENT_ID.set(current_payment_id);
+    restore_certificates(certificates.unwrap_or_default());
+    restore_subscriptions(subscriptions.unwrap_or_default());
+    restore_deposit_accounts(deposit_accounts.unwrap_or_default());
+    restore_batch_payments(batch_payments.unwrap_or_default());
+    OFFSET_RECORDED.with(|r| *r.borrow_mut() = offset_recorded.unwrap_or_default());
+    set_node_manager_canister_id(node_manager);
+    restore_queue(notifications.unwrap_or_default());
+    start_subscription_timer();
+    start_deposit_timer();
+    start_notification_timer();
+    start_proof_timer();
     // NODE_ID.set(node_id);
     CLIENT.name.clone();
 }
@@ -267,9 +526,22 @@ async fn set_offset_emissions(nodeId: Option<String>) -> String {
     }
 
     
-    let canister_id = Principal::from_text("jhfj2-iqaaa-aaaak-qddxq-cai").expect("Failed to create Principal");
+    let Some(canister_id) = node_manager_canister_id() else {
+        return serde_json::to_string(&json!({"error": "No node_manager canister is configured."})).unwrap();
+    };
     let mut client = CLIENT.clone();
-    let payment: Vec<_> = PAYMENT_STORE.with(|payments| payments.borrow().values().cloned().collect());
+    let pending: Vec<(u64, Payment)> = PAYMENT_STORE.with(|payments| {
+        OFFSET_RECORDED.with(|recorded| {
+            let recorded = recorded.borrow();
+            payments
+                .borrow()
+                .iter()
+                .filter(|(id, _)| !recorded.contains(id))
+                .map(|(id, payment)| (*id, payment.clone()))
+                .collect()
+        })
+    });
+    let payment: Vec<Payment> = pending.iter().map(|(_, payment)| payment.clone()).collect();
     
     if let Some(ref node_id) = nodeId {
         // check if the node_id is a specified string
@@ -290,11 +562,57 @@ async fn set_offset_emissions(nodeId: Option<String>) -> String {
     }
     }
     match ic_cdk::api::call::call::<(Client, Vec<Payment>, Option<String>), (String,)>(canister_id, "get_offset_emissions", (client, payment, None)).await {
-        Ok((response,)) => response,
+        Ok((response,)) => {
+            mark_offset_recorded(&pending, &response);
+            response
+        }
         Err(e) => format!("Error: {:?}", e),
     }
 }
 
+pub(crate) fn caller_is_authorized() -> bool {
+    let caller = caller();
+    AUTHORIZED_PRINCIPALS.with(|p| {
+        let authorized_principals = p.borrow();
+        authorized_principals.is_empty() || authorized_principals.contains(&caller)
+    })
+}
+
+// the stored payments among `ids`, in the order of their ids
+pub(crate) fn payments_by_id(ids: &[u64]) -> Vec<(u64, Payment)> {
+    PAYMENT_STORE.with(|store| {
+        let store = store.borrow();
+        ids.iter()
+            .filter_map(|id| store.get(id).map(|payment| (*id, payment.clone())))
+            .collect()
+    })
+}
+
+pub(crate) fn mark_offsets_recorded(ids: &[u64]) {
+    OFFSET_RECORDED.with(|r| r.borrow_mut().extend(ids.iter().copied()));
+}
+
+// remembers the payments the node_manager reported as recorded in its offset ledger
+fn mark_offset_recorded(sent: &[(u64, Payment)], response: &str) {
+    let recorded = match serde_json::from_str::<Value>(response) {
+        Ok(data) => data["recorded"].as_array().cloned().unwrap_or_default(),
+        Err(_) => return,
+    };
+    OFFSET_RECORDED.with(|r| {
+        let mut offset_recorded = r.borrow_mut();
+        for (id, payment) in sent {
+            let block_height = u64::try_from(&payment.block_height.0).unwrap_or(u64::MAX);
+            if recorded.iter().any(|key| {
+                key["block_height"].as_u64() == Some(block_height)
+                    && key["node_id"].as_str() == payment.node_id.as_deref()
+                    && key["client"].as_str() == payment.client.as_deref()
+            }) {
+                offset_recorded.insert(*id);
+            }
+        }
+    });
+}
+
 #[query(name = "getPurchasesByNodeId")]
 fn get_purchases_by_node_id(node_id: String) -> Vec<Payment> {
     let node_id_clone = node_id.clone();
@@ -307,6 +625,24 @@ fn get_purchases_by_node_id(node_id: String) -> Vec<Payment> {
     })
 }
 
+#[query(name = "getPurchasesByNodeIdCertified")]
+fn get_purchases_by_node_id_certified(node_id: String) -> CertifiedPayments {
+    let payments = PAYMENT_STORE.with(|store| {
+        store
+            .borrow()
+            .iter()
+            .filter(|(_, payment)| payment.node_id.as_ref() == Some(&node_id))
+            .map(|(id, payment)| (*id, payment.clone()))
+            .collect()
+    });
+    let (certificate, witness) = payments_witness();
+    CertifiedPayments {
+        payments,
+        certificate,
+        witness,
+    }
+}
+
 #[update(name = "get_proof")]
 pub async fn get_proof(contribution_id: String) -> String {
     let json = get_contribution_by_id(contribution_id.clone()).await;
@@ -445,6 +781,7 @@ fn delete_payments_with_no_proof() -> String {
 
         for key in keys_to_delete {
             store_borrowed.remove(&key);
+            uncertify_payment(key);
             payments_deleted += 1;
         }
     });
@@ -1,2 +1,10 @@
+mod batch_payments;
 mod cawa_poster;
+mod certificate_nft;
+mod certification;
+mod deposits;
+mod notifications;
+mod subscriptions;
+mod units;
+// export_candid! in esg_wallet only picks up methods of the modules declared above it
 mod esg_wallet;
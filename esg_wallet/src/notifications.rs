@@ -0,0 +1,212 @@
+use std::{cell::RefCell, collections::BTreeMap, time::Duration};
+
+use candid::{CandidType, Principal};
+use ic_cdk::{query, update};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::esg_wallet::{caller_is_authorized, mark_offsets_recorded, payments_by_id, Payment};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// how often the timer delivers the notifications that are due
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_BATCH_SIZE: usize = 50;
+// the delay before a retry doubles with every attempt, up to a day
+const RETRY_BASE_SECONDS: u64 = 60;
+const MAX_RETRY_SECONDS: u64 = 24 * 60 * 60;
+// a notification is kept but no longer retried on its own after this many attempts
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Settled payment the node_manager was not told about yet.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PendingNotification {
+    pub payment_id: u64,
+    pub attempts: u32,
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+}
+
+// key of a payment in the offset ledger of the node_manager
+#[derive(CandidType, Deserialize)]
+struct PaymentKey {
+    block_height: u64,
+    node_id: Option<String>,
+    client: Option<String>,
+}
+
+thread_local! {
+    static NODE_MANAGER_CANISTER_ID: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    static QUEUE: RefCell<BTreeMap<u64, PendingNotification>> = RefCell::default();
+    static DELIVERY_IN_PROGRESS: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// Delivery run in progress, the flag is cleared when it is dropped. That also happens when the
+// callback of the call to the node_manager traps, so a trap does not stop deliveries for good.
+struct Delivery;
+
+impl Delivery {
+    fn start() -> Option<Delivery> {
+        (!DELIVERY_IN_PROGRESS.with(|d| d.replace(true))).then_some(Delivery)
+    }
+}
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        DELIVERY_IN_PROGRESS.with(|d| d.replace(false));
+    }
+}
+
+pub fn node_manager_canister_id() -> Option<Principal> {
+    NODE_MANAGER_CANISTER_ID.with(|n| *n.borrow())
+}
+
+pub fn set_node_manager_canister_id(canister_id: Option<Principal>) {
+    NODE_MANAGER_CANISTER_ID.with(|n| *n.borrow_mut() = canister_id);
+}
+
+pub fn queue_snapshot() -> BTreeMap<u64, PendingNotification> {
+    QUEUE.with(|q| q.borrow().clone())
+}
+
+pub fn restore_queue(queue: BTreeMap<u64, PendingNotification>) {
+    QUEUE.with(|q| *q.borrow_mut() = queue);
+}
+
+/// Starts the periodic delivery, has to be called from init and post_upgrade.
+pub fn start_notification_timer() {
+    ic_cdk_timers::set_timer_interval(DELIVERY_INTERVAL, || {
+        ic_cdk::spawn(deliver_due_notifications())
+    });
+}
+
+/// Queues a settled payment, it is delivered with the next run of the timer.
+pub fn enqueue(payment_id: u64) {
+    QUEUE.with(|q| {
+        q.borrow_mut().insert(
+            payment_id,
+            PendingNotification {
+                payment_id,
+                attempts: 0,
+                next_attempt_at: 0,
+                last_error: None,
+            },
+        )
+    });
+}
+
+fn retry_delay(attempts: u32) -> u64 {
+    let seconds = RETRY_BASE_SECONDS.saturating_mul(1 << attempts.min(20));
+    seconds.min(MAX_RETRY_SECONDS) * NANOS_PER_SECOND
+}
+
+// Sends the notifications that are due to the node_manager. A notification leaves the queue
+// once the node_manager reports the payment as recorded, until then it is retried with backoff,
+// so a payment may be delivered more than once; the offset ledger skips the repeats.
+async fn deliver_due_notifications() {
+    let Some(node_manager) = node_manager_canister_id() else {
+        return;
+    };
+    let Some(_delivery) = Delivery::start() else {
+        return;
+    };
+
+    let now = ic_cdk::api::time();
+    let due: Vec<u64> = QUEUE.with(|q| {
+        q.borrow()
+            .values()
+            .filter(|n| n.attempts < MAX_ATTEMPTS && n.next_attempt_at <= now)
+            .map(|n| n.payment_id)
+            .take(MAX_BATCH_SIZE)
+            .collect()
+    });
+    let payments = payments_by_id(&due);
+    // payments that were deleted in the meantime have nothing left to deliver
+    QUEUE.with(|q| {
+        let mut queue = q.borrow_mut();
+        for id in &due {
+            if !payments.iter().any(|(payment_id, _)| payment_id == id) {
+                queue.remove(id);
+            }
+        }
+    });
+
+    if !payments.is_empty() {
+        let sent: Vec<Payment> = payments.iter().map(|(_, payment)| payment.clone()).collect();
+        let result = ic_cdk::api::call::call::<(Vec<Payment>,), (Result<Vec<PaymentKey>, String>,)>(
+            node_manager,
+            "notify_settled_payments",
+            (sent,),
+        )
+        .await
+        .map_err(|(code, message)| format!("{:?}: {}", code, message))
+        .and_then(|(result,)| result);
+
+        let recorded: Vec<u64> = match &result {
+            Ok(keys) => payments
+                .iter()
+                .filter(|(_, payment)| {
+                    let block_height = u64::try_from(&payment.block_height.0).unwrap_or(u64::MAX);
+                    keys.iter().any(|key| {
+                        key.block_height == block_height
+                            && key.node_id == payment.node_id
+                            && key.client == payment.client
+                    })
+                })
+                .map(|(id, _)| *id)
+                .collect(),
+            Err(_) => vec![],
+        };
+        mark_offsets_recorded(&recorded);
+
+        let now = ic_cdk::api::time();
+        QUEUE.with(|q| {
+            let mut queue = q.borrow_mut();
+            for (id, _) in &payments {
+                if recorded.contains(id) {
+                    queue.remove(id);
+                } else if let Some(notification) = queue.get_mut(id) {
+                    notification.attempts += 1;
+                    notification.next_attempt_at = now + retry_delay(notification.attempts);
+                    notification.last_error = Some(match &result {
+                        Ok(_) => "The node_manager did not record the payment".to_string(),
+                        Err(e) => e.clone(),
+                    });
+                }
+            }
+        });
+    }
+}
+
+#[query(name = "getPendingNotifications")]
+fn get_pending_notifications() -> Vec<PendingNotification> {
+    QUEUE.with(|q| q.borrow().values().cloned().collect())
+}
+
+// makes every queued notification due again, including the ones that ran out of attempts
+#[update(name = "retryNotifications")]
+fn retry_notifications() -> Result<u64, String> {
+    if !caller_is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    QUEUE.with(|q| {
+        let mut queue = q.borrow_mut();
+        for notification in queue.values_mut() {
+            notification.attempts = 0;
+            notification.next_attempt_at = 0;
+        }
+        Ok(queue.len() as u64)
+    })
+}
+
+#[update(name = "setNodeManagerCanisterId")]
+fn set_node_manager(canister_id: Principal) -> Result<(), String> {
+    if !caller_is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    set_node_manager_canister_id(Some(canister_id));
+    Ok(())
+}
+
+#[query(name = "getNodeManagerCanisterId")]
+fn get_node_manager() -> Option<Principal> {
+    node_manager_canister_id()
+}
@@ -0,0 +1,278 @@
+use std::{cell::RefCell, collections::BTreeMap, time::Duration};
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::{caller, query, update};
+use icrc_ledger_types::icrc1::account::Account;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::cawa_poster::caller_may_contribute;
+use crate::esg_wallet::{get_price, settle_payment, transfer_from_payer};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// how often the timer looks for subscriptions that are due
+const CHARGE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MIN_PERIOD_SECONDS: u64 = 24 * 60 * 60;
+const MAX_TICKET_COUNT: u64 = 1000000;
+// a subscription is marked as failed after this many charges in a row did not go through
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+// charges kept per subscription, older ones are dropped
+const MAX_CHARGE_HISTORY: usize = 100;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SubscriptionStatus {
+    Active,
+    Paused,
+    Cancelled,
+    Failed,
+    Ended,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SubscriptionCharge {
+    pub charged_at: u64,
+    pub ticket_count: u64,
+    pub payment_id: Option<u64>,
+    pub block_height: Option<Nat>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Subscription {
+    pub id: u64,
+    pub payer: Principal,
+    pub ticket_count: u64,
+    pub period_seconds: u64,
+    pub node_id: Option<String>,
+    pub created_at: u64,
+    pub end_at: Option<u64>,
+    pub next_charge_at: u64,
+    pub status: SubscriptionStatus,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub charges: Vec<SubscriptionCharge>,
+}
+
+thread_local! {
+    static SUBSCRIPTIONS: RefCell<BTreeMap<u64, Subscription>> = RefCell::default();
+}
+
+pub fn subscriptions_snapshot() -> BTreeMap<u64, Subscription> {
+    SUBSCRIPTIONS.with(|s| s.borrow().clone())
+}
+
+pub fn restore_subscriptions(subscriptions: BTreeMap<u64, Subscription>) {
+    SUBSCRIPTIONS.with(|s| *s.borrow_mut() = subscriptions);
+}
+
+/// Starts the periodic charge job, has to be called from init and post_upgrade.
+pub fn start_subscription_timer() {
+    ic_cdk_timers::set_timer_interval(CHARGE_CHECK_INTERVAL, || {
+        ic_cdk::spawn(charge_due_subscriptions())
+    });
+}
+
+// Charges every active subscription whose period is due, once. The next charge time is moved to
+// the first period after now before the ledger call, so an overlapping run can never charge twice
+// and periods missed while the canister was stopped are skipped instead of charged back-to-back.
+async fn charge_due_subscriptions() {
+    let now = ic_cdk::api::time();
+    let due: Vec<Subscription> = SUBSCRIPTIONS.with(|s| {
+        let mut subscriptions = s.borrow_mut();
+        let mut due = vec![];
+        for subscription in subscriptions.values_mut() {
+            if subscription.status != SubscriptionStatus::Active || subscription.next_charge_at > now {
+                continue;
+            }
+            if subscription.end_at.is_some_and(|end_at| end_at <= now) {
+                subscription.status = SubscriptionStatus::Ended;
+                continue;
+            }
+            let period = subscription.period_seconds * NANOS_PER_SECOND;
+            let missed = (now - subscription.next_charge_at) / period;
+            subscription.next_charge_at += (missed + 1) * period;
+            due.push(subscription.clone());
+        }
+        due
+    });
+
+    for subscription in due {
+        let charge = charge_subscription(&subscription).await;
+        SUBSCRIPTIONS.with(|s| {
+            if let Some(stored) = s.borrow_mut().get_mut(&subscription.id) {
+                match &charge.error {
+                    Some(error) => {
+                        stored.consecutive_failures += 1;
+                        stored.last_error = Some(error.clone());
+                        if stored.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+                            stored.status = SubscriptionStatus::Failed;
+                        }
+                    }
+                    None => {
+                        stored.consecutive_failures = 0;
+                        stored.last_error = None;
+                    }
+                }
+                stored.charges.push(charge);
+                let dropped = stored.charges.len().saturating_sub(MAX_CHARGE_HISTORY);
+                stored.charges.drain(..dropped);
+            }
+        });
+    }
+}
+
+async fn charge_subscription(subscription: &Subscription) -> SubscriptionCharge {
+    let from = Account {
+        owner: subscription.payer,
+        subaccount: None,
+    };
+    let amount = get_price(subscription.ticket_count as f64) as u64;
+    let mut charge = SubscriptionCharge {
+        charged_at: ic_cdk::api::time(),
+        ticket_count: subscription.ticket_count,
+        payment_id: None,
+        block_height: None,
+        error: None,
+    };
+
+    match transfer_from_payer(from, amount).await {
+        Ok(block_height) => {
+            let (payment_id, _) = settle_payment(
+                subscription.payer,
+                block_height.clone(),
+                subscription.ticket_count,
+                subscription.node_id.clone(),
+            )
+            .await;
+            charge.payment_id = Some(payment_id);
+            charge.block_height = Some(block_height);
+        }
+        Err(error) => charge.error = Some(error),
+    }
+    charge
+}
+
+// applies a status change requested by the payer of the subscription
+fn update_status(
+    subscription_id: u64,
+    allowed_from: &[SubscriptionStatus],
+    status: SubscriptionStatus,
+) -> Result<Subscription, String> {
+    let caller = caller();
+    SUBSCRIPTIONS.with(|s| {
+        let mut subscriptions = s.borrow_mut();
+        let subscription = subscriptions
+            .get_mut(&subscription_id)
+            .filter(|subscription| subscription.payer == caller)
+            .ok_or_else(|| format!("Subscription {} not found", subscription_id))?;
+
+        if !allowed_from.contains(&subscription.status) {
+            return Err(format!(
+                "Subscription {} cannot go from {:?} to {:?}",
+                subscription_id, subscription.status, status
+            ));
+        }
+
+        if status == SubscriptionStatus::Active {
+            // resuming never back-charges the periods missed while paused
+            let now = ic_cdk::api::time();
+            subscription.next_charge_at = subscription.next_charge_at.max(now);
+            subscription.consecutive_failures = 0;
+        }
+        subscription.status = status;
+        Ok(subscription.clone())
+    })
+}
+
+/// Creates a subscription for the caller. The caller has to keep an ICRC-2 allowance for this
+/// canister that covers the charges, the first charge happens on the next timer run.
+#[update(name = "createSubscription")]
+fn create_subscription(
+    ticket_count: u64,
+    period_seconds: u64,
+    node_id: Option<String>,
+    end_at: Option<u64>,
+) -> Result<Subscription, String> {
+    if ticket_count == 0 {
+        return Err("Invalid ticket count".to_string());
+    }
+    if ticket_count > MAX_TICKET_COUNT {
+        return Err("Ticket count is too big".to_string());
+    }
+    if period_seconds < MIN_PERIOD_SECONDS {
+        return Err(format!("Period must be at least {} seconds", MIN_PERIOD_SECONDS));
+    }
+    if !caller_may_contribute() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    if end_at.is_some_and(|end_at| end_at <= now) {
+        return Err("End date must be in the future".to_string());
+    }
+
+    SUBSCRIPTIONS.with(|s| {
+        let mut subscriptions = s.borrow_mut();
+        let id = subscriptions.keys().next_back().map_or(1, |id| id + 1);
+        let subscription = Subscription {
+            id,
+            payer: caller(),
+            ticket_count,
+            period_seconds,
+            node_id,
+            created_at: now,
+            end_at,
+            next_charge_at: now,
+            status: SubscriptionStatus::Active,
+            consecutive_failures: 0,
+            last_error: None,
+            charges: vec![],
+        };
+        subscriptions.insert(id, subscription.clone());
+        Ok(subscription)
+    })
+}
+
+#[update(name = "pauseSubscription")]
+fn pause_subscription(subscription_id: u64) -> Result<Subscription, String> {
+    update_status(
+        subscription_id,
+        &[SubscriptionStatus::Active],
+        SubscriptionStatus::Paused,
+    )
+}
+
+// also used to restart a subscription that failed, e.g. after the allowance was topped up
+#[update(name = "resumeSubscription")]
+fn resume_subscription(subscription_id: u64) -> Result<Subscription, String> {
+    update_status(
+        subscription_id,
+        &[SubscriptionStatus::Paused, SubscriptionStatus::Failed],
+        SubscriptionStatus::Active,
+    )
+}
+
+#[update(name = "cancelSubscription")]
+fn cancel_subscription(subscription_id: u64) -> Result<Subscription, String> {
+    update_status(
+        subscription_id,
+        &[
+            SubscriptionStatus::Active,
+            SubscriptionStatus::Paused,
+            SubscriptionStatus::Failed,
+        ],
+        SubscriptionStatus::Cancelled,
+    )
+}
+
+// subscriptions of the caller, including their latest charges
+#[query(name = "getSubscriptions")]
+fn get_subscriptions() -> Vec<Subscription> {
+    let caller = caller();
+    SUBSCRIPTIONS.with(|s| {
+        s.borrow()
+            .values()
+            .filter(|subscription| subscription.payer == caller)
+            .cloned()
+            .collect()
+    })
+}
@@ -0,0 +1,44 @@
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Co2eUnit {
+    Grams,
+    Kilograms,
+    Tonnes,
+}
+
+impl Co2eUnit {
+    fn grams(self) -> u64 {
+        match self {
+            Co2eUnit::Grams => 1,
+            Co2eUnit::Kilograms => 1_000,
+            Co2eUnit::Tonnes => 1_000_000,
+        }
+    }
+}
+
+/// Quantity of CO2e in whole grams, the same type node_manager reads payments with.
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct Co2e {
+    pub grams: u64,
+}
+
+impl Co2e {
+    pub fn new(value: u64, unit: Co2eUnit) -> Co2e {
+        Co2e {
+            grams: value.saturating_mul(unit.grams()),
+        }
+    }
+
+    pub fn in_unit(self, unit: Co2eUnit) -> f64 {
+        self.grams as f64 / unit.grams() as f64
+    }
+
+    /// The quantity as a whole number of `unit`, None when it is not one.
+    pub fn whole(self, unit: Co2eUnit) -> Option<u64> {
+        self.grams.is_multiple_of(unit.grams()).then(|| self.grams / unit.grams())
+    }
+}
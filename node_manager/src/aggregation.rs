@@ -0,0 +1,96 @@
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::node_registry::{self, NodeMetadata};
+use crate::units::Co2e;
+
+/// Dimension of the node registry emissions are aggregated along.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EmissionsGrouping {
+    Node,
+    Subnet,
+    Provider,
+    DataCenter,
+    Country,
+    GridRegion,
+}
+
+// Emissions of a group of nodes. `total_emissions` is what the nodes emitted, `net_emissions` what
+// is left of it after the offsets.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct EmissionsSummary {
+    pub key: String,
+    pub node_count: u64,
+    pub total_emissions: Co2e,
+    pub offset_emissions: Co2e,
+    pub net_emissions: Co2e,
+}
+
+/// Emissions of one node: id, emissions left and emissions offset.
+pub type NodeEmissions = (String, Co2e, Co2e);
+
+fn group_key(grouping: EmissionsGrouping, node_id: &str, metadata: Option<NodeMetadata>) -> Option<String> {
+    if grouping == EmissionsGrouping::Node {
+        return Some(node_id.to_string());
+    }
+    let metadata = metadata?;
+    match grouping {
+        EmissionsGrouping::Node => None,
+        EmissionsGrouping::Subnet => metadata.subnet_id,
+        EmissionsGrouping::Provider => metadata.node_provider,
+        EmissionsGrouping::DataCenter => metadata.data_center,
+        EmissionsGrouping::Country => metadata.country,
+        EmissionsGrouping::GridRegion => metadata.grid_region,
+    }
+}
+
+/// Sums the emissions per group, nodes the registry has no value for are left out.
+pub fn summarize(
+    nodes: impl Iterator<Item = NodeEmissions>,
+    grouping: EmissionsGrouping,
+) -> Vec<EmissionsSummary> {
+    let mut groups: BTreeMap<String, EmissionsSummary> = BTreeMap::new();
+    for (node_id, net_emissions, offset_emissions) in nodes {
+        let Some(key) = group_key(grouping, &node_id, node_registry::get(&node_id)) else {
+            continue;
+        };
+        let summary = groups.entry(key.clone()).or_insert_with(|| EmissionsSummary {
+            key,
+            ..Default::default()
+        });
+        summary.node_count += 1;
+        summary.total_emissions += net_emissions + offset_emissions;
+        summary.offset_emissions += offset_emissions;
+        summary.net_emissions += net_emissions;
+    }
+    groups.into_values().collect()
+}
+
+/// Summary of one group, zero emissions when no node belongs to it.
+pub fn summarize_group(
+    nodes: impl Iterator<Item = NodeEmissions>,
+    grouping: EmissionsGrouping,
+    key: &str,
+) -> EmissionsSummary {
+    summarize(nodes, grouping)
+        .into_iter()
+        .find(|summary| summary.key == key)
+        .unwrap_or_else(|| EmissionsSummary {
+            key: key.to_string(),
+            ..Default::default()
+        })
+}
+
+/// The `limit` groups with the highest net emissions.
+pub fn ranking(
+    nodes: impl Iterator<Item = NodeEmissions>,
+    grouping: EmissionsGrouping,
+    limit: usize,
+) -> Vec<EmissionsSummary> {
+    let mut summaries = summarize(nodes, grouping);
+    summaries.sort_by_key(|summary| Reverse(summary.net_emissions));
+    summaries.truncate(limit);
+    summaries
+}
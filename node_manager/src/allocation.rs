@@ -0,0 +1,224 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+// amounts below this are treated as zero, they only come from floating point rounding
+const EPSILON: f64 = 1e-9;
+
+/// How an offset is split over the nodes it is applied to. Every strategy hands out the full
+/// offset unless the nodes have less emissions left than that.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum AllocationStrategy {
+    // shares proportional to the emissions every node has left
+    Proportional,
+    // fills the node with the most emissions first, then the next one
+    #[default]
+    HighestEmitterFirst,
+    EqualSplit,
+    // every offset starts at the next node, nodes in name order
+    RoundRobin,
+    // shares proportional to the node weights set for the client
+    Weighted,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AllocationSettings {
+    pub default_strategy: AllocationStrategy,
+    pub client_strategies: Vec<(String, AllocationStrategy)>,
+    pub client_weights: Vec<(String, Vec<(String, f64)>)>,
+    pub round_robin_cursor: u64,
+}
+
+thread_local! {
+    static DEFAULT_STRATEGY: RefCell<AllocationStrategy> = RefCell::default();
+    static CLIENT_STRATEGIES: RefCell<BTreeMap<String, AllocationStrategy>> = RefCell::default();
+    static CLIENT_WEIGHTS: RefCell<BTreeMap<String, BTreeMap<String, f64>>> = RefCell::default();
+    static ROUND_ROBIN_CURSOR: RefCell<u64> = RefCell::default();
+}
+
+pub fn settings_snapshot() -> AllocationSettings {
+    AllocationSettings {
+        default_strategy: DEFAULT_STRATEGY.with(|s| s.borrow().clone()),
+        client_strategies: CLIENT_STRATEGIES.with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(client, strategy)| (client.clone(), strategy.clone()))
+                .collect()
+        }),
+        client_weights: CLIENT_WEIGHTS.with(|w| {
+            w.borrow()
+                .iter()
+                .map(|(client, weights)| {
+                    let weights = weights.iter().map(|(node, weight)| (node.clone(), *weight));
+                    (client.clone(), weights.collect())
+                })
+                .collect()
+        }),
+        round_robin_cursor: ROUND_ROBIN_CURSOR.with(|c| *c.borrow()),
+    }
+}
+
+pub fn restore_settings(settings: AllocationSettings) {
+    DEFAULT_STRATEGY.with(|s| *s.borrow_mut() = settings.default_strategy);
+    CLIENT_STRATEGIES.with(|s| *s.borrow_mut() = settings.client_strategies.into_iter().collect());
+    CLIENT_WEIGHTS.with(|w| {
+        *w.borrow_mut() = settings
+            .client_weights
+            .into_iter()
+            .map(|(client, weights)| (client, weights.into_iter().collect()))
+            .collect()
+    });
+    ROUND_ROBIN_CURSOR.with(|c| *c.borrow_mut() = settings.round_robin_cursor);
+}
+
+/// Sets the strategy of a client, or the default strategy when no client is given.
+pub fn set_strategy(client: Option<String>, strategy: AllocationStrategy) {
+    match client {
+        Some(client) => CLIENT_STRATEGIES.with(|s| s.borrow_mut().insert(client, strategy)),
+        None => Some(DEFAULT_STRATEGY.with(|s| s.replace(strategy))),
+    };
+}
+
+pub fn clear_strategy(client: &str) {
+    CLIENT_STRATEGIES.with(|s| s.borrow_mut().remove(client));
+}
+
+pub fn set_weights(client: String, weights: Vec<(String, f64)>) -> Result<(), String> {
+    if weights
+        .iter()
+        .any(|(_, weight)| !weight.is_finite() || *weight < 0.0)
+    {
+        return Err("Weights must be zero or positive".to_string());
+    }
+    CLIENT_WEIGHTS.with(|w| w.borrow_mut().insert(client, weights.into_iter().collect()));
+    Ok(())
+}
+
+fn strategy_of(client: &str) -> AllocationStrategy {
+    CLIENT_STRATEGIES
+        .with(|s| s.borrow().get(client).cloned())
+        .unwrap_or_else(|| DEFAULT_STRATEGY.with(|s| s.borrow().clone()))
+}
+
+/// Splits `offset` over nodes with the given emissions left, using the strategy of the client.
+/// The shares are in the order of `nodes`, never exceed a node's emissions and add up to the
+/// offset, or to the emissions left when those are smaller.
+pub fn allocate(client: &str, nodes: &[(String, f64)], offset: f64) -> Vec<f64> {
+    let capacities: Vec<f64> = nodes.iter().map(|(_, emissions)| emissions.max(0.0)).collect();
+    if offset <= 0.0 {
+        return vec![0.0; nodes.len()];
+    }
+
+    match strategy_of(client) {
+        AllocationStrategy::Proportional => fill(&capacities, &capacities, offset),
+        AllocationStrategy::EqualSplit => fill(&capacities, &vec![1.0; nodes.len()], offset),
+        AllocationStrategy::Weighted => {
+            let weights: Vec<f64> = CLIENT_WEIGHTS.with(|w| {
+                let client_weights = w.borrow();
+                let client_weights = client_weights.get(client);
+                nodes
+                    .iter()
+                    .map(|(node, _)| {
+                        client_weights
+                            .and_then(|weights| weights.get(node))
+                            .copied()
+                            .unwrap_or(0.0)
+                    })
+                    .collect()
+            });
+            // without a preference for any of the nodes the split falls back to proportional
+            if weights.iter().sum::<f64>() <= 0.0 {
+                return fill(&capacities, &capacities, offset);
+            }
+            // what the weighted nodes cannot take goes to the nodes with emissions left, in
+            // proportion to those emissions
+            let mut shares = fill(&capacities, &weights, offset);
+            let remaining = offset - shares.iter().sum::<f64>();
+            if remaining > EPSILON {
+                let left: Vec<f64> = capacities.iter().zip(&shares).map(|(c, s)| c - s).collect();
+                for (share, spill) in shares.iter_mut().zip(fill(&left, &left, remaining)) {
+                    *share += spill;
+                }
+            }
+            shares
+        }
+        AllocationStrategy::HighestEmitterFirst => {
+            let mut order: Vec<usize> = (0..nodes.len()).collect();
+            order.sort_by(|a, b| capacities[*b].total_cmp(&capacities[*a]));
+            fill_in_order(&capacities, &order, offset)
+        }
+        AllocationStrategy::RoundRobin => {
+            let mut order: Vec<usize> = (0..nodes.len()).collect();
+            order.sort_by(|a, b| nodes[*a].0.cmp(&nodes[*b].0));
+            if !order.is_empty() {
+                let cursor = ROUND_ROBIN_CURSOR.with(|c| {
+                    let mut cursor = c.borrow_mut();
+                    *cursor = cursor.wrapping_add(1);
+                    *cursor - 1
+                });
+                let start = (cursor % order.len() as u64) as usize;
+                order.rotate_left(start);
+            }
+            fill_in_order(&capacities, &order, offset)
+        }
+    }
+}
+
+// gives every node, in the given order, as much as it can take until the offset is used up
+fn fill_in_order(capacities: &[f64], order: &[usize], offset: f64) -> Vec<f64> {
+    let mut shares = vec![0.0; capacities.len()];
+    let mut remaining = offset;
+    for &i in order {
+        if remaining <= EPSILON {
+            break;
+        }
+        shares[i] = remaining.min(capacities[i]);
+        remaining -= shares[i];
+    }
+    shares
+}
+
+// Splits the offset proportional to the weights. Nodes that reach their capacity drop out and
+// what they could not take is split again over the others, every round fills up at least one
+// node or hands out the rest, so there are at most as many rounds as nodes.
+fn fill(capacities: &[f64], weights: &[f64], offset: f64) -> Vec<f64> {
+    let mut shares = vec![0.0; capacities.len()];
+    let mut remaining = offset;
+    for _ in 0..capacities.len() {
+        let open: Vec<usize> = (0..capacities.len())
+            .filter(|&i| weights[i] > 0.0 && capacities[i] - shares[i] > EPSILON)
+            .collect();
+        let total_weight: f64 = open.iter().map(|&i| weights[i]).sum();
+        if remaining <= EPSILON || total_weight <= 0.0 {
+            break;
+        }
+
+        let mut handed_out = 0.0;
+        for &i in &open {
+            let share = (remaining * weights[i] / total_weight).min(capacities[i] - shares[i]);
+            shares[i] += share;
+            handed_out += share;
+        }
+        remaining -= handed_out;
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_spills_over_to_unweighted_nodes() {
+        set_strategy(Some("client".to_string()), AllocationStrategy::Weighted);
+        set_weights("client".to_string(), vec![("a".to_string(), 1.0)]).unwrap();
+        let nodes = vec![("a".to_string(), 2.0), ("b".to_string(), 6.0), ("c".to_string(), 2.0)];
+
+        let shares = allocate("client", &nodes, 6.0);
+        assert!((shares[0] - 2.0).abs() < EPSILON);
+        assert!((shares[1] - 3.0).abs() < EPSILON);
+        assert!((shares[2] - 1.0).abs() < EPSILON);
+        assert!((shares.iter().sum::<f64>() - 6.0).abs() < EPSILON);
+    }
+}
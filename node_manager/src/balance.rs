@@ -0,0 +1,91 @@
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::offset_ledger::{LedgerEntry, LedgerEntryKind};
+use crate::units::Co2e;
+
+// Carbon balance of a client over a period. `offsets` are the offsets the ledger recorded for the
+// client, `reversed` the part of them that was reversed since; the balance uses offsets minus
+// reversals.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CarbonBalance {
+    pub client: String,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub emissions: Co2e,
+    pub offsets: Co2e,
+    pub reversed: Co2e,
+    pub deficit: Co2e,
+    pub surplus: Co2e,
+    pub net_zero: bool,
+    pub kilos_per_ticket: f64,
+    // project or vendor the rate was set for, None for the default rate
+    pub rate_source: Option<String>,
+    pub tickets_to_net_zero: u64,
+    // price of one ticket as of the latest payment a wallet sent, None before the first one
+    pub ticket_price: Option<f64>,
+    pub cost_to_net_zero: Option<f64>,
+}
+
+/// CO2e of a ledger entry that belongs to a client: all of it when the payment was made for the
+/// client, of payments without a client the allocations to its nodes. None for other clients.
+pub fn attributed_co2e(client: &str, node_ids: &[String], entry: &LedgerEntry) -> Option<Co2e> {
+    match &entry.payment.client {
+        Some(payment_client) if payment_client == client => Some(entry.co2e),
+        Some(_) => None,
+        None => Some(
+            entry
+                .allocations
+                .iter()
+                .filter(|allocation| node_ids.contains(&allocation.node))
+                .map(|allocation| allocation.offset)
+                .sum(),
+        ),
+    }
+}
+
+/// Offsets and reversals of the ledger that belong to a client, see `attributed_co2e`.
+pub fn client_offsets(client: &str, node_ids: &[String], entries: &[LedgerEntry]) -> (Co2e, Co2e) {
+    let mut offsets = Co2e::ZERO;
+    let mut reversed = Co2e::ZERO;
+    for entry in entries {
+        let Some(co2e) = attributed_co2e(client, node_ids, entry) else {
+            continue;
+        };
+        match entry.kind {
+            LedgerEntryKind::Offset => offsets += co2e,
+            LedgerEntryKind::Reversal => reversed += co2e,
+        }
+    }
+    (offsets, reversed)
+}
+
+pub fn balance(
+    client: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    emissions: Co2e,
+    (offsets, reversed): (Co2e, Co2e),
+    (kilos_per_ticket, rate_source): (f64, Option<String>),
+    ticket_price: Option<f64>,
+) -> CarbonBalance {
+    let offset = offsets - reversed;
+    let deficit = emissions - offset;
+    let tickets_to_net_zero = (deficit.kilos() / kilos_per_ticket).ceil() as u64;
+    CarbonBalance {
+        client,
+        from,
+        to,
+        emissions,
+        offsets,
+        reversed,
+        deficit,
+        surplus: offset - emissions,
+        net_zero: deficit.is_zero(),
+        kilos_per_ticket,
+        rate_source,
+        tickets_to_net_zero,
+        ticket_price,
+        cost_to_net_zero: ticket_price.map(|price| price * tickets_to_net_zero as f64),
+    }
+}
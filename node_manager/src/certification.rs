@@ -0,0 +1,76 @@
+use std::cell::RefCell;
+
+use candid::CandidType;
+use ic_cdk::api::{data_certificate, set_certified_data};
+use ic_certified_map::{labeled, labeled_hash, leaf_hash, AsHashTree, Hash, HashTree, RbTree};
+use serde::Serialize;
+
+// label under which the node offset tree is certified
+const NODES_LABEL: &[u8] = b"nodes";
+
+thread_local! {
+    static NODE_TREE: RefCell<RbTree<Vec<u8>, Hash>> = const { RefCell::new(RbTree::new()) };
+}
+
+/// Hash of the candid encoding of a value, which is what the frontend recomputes
+/// to check a returned record against the witness.
+fn value_hash<T: CandidType>(value: &T) -> Hash {
+    let bytes = candid::encode_one(value).expect("Failed to encode value for certification");
+    leaf_hash(&bytes)
+}
+
+fn update_certified_data() {
+    NODE_TREE.with(|tree| {
+        set_certified_data(&labeled_hash(NODES_LABEL, &tree.borrow().root_hash()));
+    });
+}
+
+/// Adds (or replaces) the offset state of a node and refreshes the certified data.
+pub fn certify_node<T: CandidType>(node_name: &str, node: &T) {
+    NODE_TREE.with(|tree| {
+        tree.borrow_mut()
+            .insert(node_name.as_bytes().to_vec(), value_hash(node));
+    });
+    update_certified_data();
+}
+
+/// Rebuilds the whole tree, used after an upgrade when the heap state is restored.
+pub fn certify_all<'a, T: CandidType + 'a>(nodes: impl Iterator<Item = (&'a str, &'a T)>) {
+    NODE_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        *tree = RbTree::new();
+        for (node_name, node) in nodes {
+            tree.insert(node_name.as_bytes().to_vec(), value_hash(node));
+        }
+    });
+    update_certified_data();
+}
+
+fn encode_witness(tree: HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    tree.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
+
+/// Returns the data certificate and a CBOR encoded witness for a single node.
+///
+/// The witness also proves absence, so a missing node can be verified as well.
+pub fn node_witness(node_name: &str) -> (Vec<u8>, Vec<u8>) {
+    let certificate = data_certificate().unwrap_or_default();
+    let witness = NODE_TREE.with(|tree| {
+        encode_witness(labeled(
+            NODES_LABEL,
+            tree.borrow().witness(node_name.as_bytes()),
+        ))
+    });
+    (certificate, witness)
+}
+
+/// Returns the data certificate and a CBOR encoded witness covering every node.
+pub fn nodes_witness() -> (Vec<u8>, Vec<u8>) {
+    let certificate = data_certificate().unwrap_or_default();
+    let witness = NODE_TREE
+        .with(|tree| encode_witness(labeled(NODES_LABEL, tree.borrow().as_hash_tree())));
+    (certificate, witness)
+}
@@ -0,0 +1,143 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+const MAX_CLIENT_NODES: usize = 1000;
+
+/// A client and the nodes it runs on.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ClientRecord {
+    pub name: String,
+    pub node_ids: Vec<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+thread_local! {
+    static CLIENTS: RefCell<BTreeMap<String, ClientRecord>> = RefCell::default();
+    // clients of every node, rebuilt from CLIENTS on upgrade
+    static NODE_CLIENTS: RefCell<BTreeMap<String, BTreeSet<String>>> = RefCell::default();
+}
+
+pub fn clients_snapshot() -> Vec<ClientRecord> {
+    CLIENTS.with(|c| c.borrow().values().cloned().collect())
+}
+
+pub fn restore_clients(clients: Vec<ClientRecord>) {
+    CLIENTS.with(|c| *c.borrow_mut() = BTreeMap::new());
+    NODE_CLIENTS.with(|n| *n.borrow_mut() = BTreeMap::new());
+    for client in clients {
+        store(client);
+    }
+}
+
+// writes a client and keeps the node index in sync
+fn store(client: ClientRecord) {
+    let previous = CLIENTS.with(|c| c.borrow_mut().insert(client.name.clone(), client.clone()));
+    NODE_CLIENTS.with(|n| {
+        let mut index = n.borrow_mut();
+        for node_id in previous.iter().flat_map(|previous| &previous.node_ids) {
+            if let Some(clients) = index.get_mut(node_id) {
+                clients.remove(&client.name);
+                if clients.is_empty() {
+                    index.remove(node_id);
+                }
+            }
+        }
+        for node_id in &client.node_ids {
+            index
+                .entry(node_id.clone())
+                .or_default()
+                .insert(client.name.clone());
+        }
+    });
+}
+
+// trimmed, deduplicated node ids in their original order
+fn normalize_nodes(node_ids: Vec<String>) -> Result<Vec<String>, String> {
+    let mut seen = BTreeSet::new();
+    let node_ids: Vec<String> = node_ids
+        .into_iter()
+        .map(|node_id| node_id.trim().to_string())
+        .filter(|node_id| seen.insert(node_id.clone()))
+        .collect();
+    if node_ids.iter().any(|node_id| node_id.is_empty()) {
+        return Err("Node ids must not be empty".to_string());
+    }
+    if node_ids.len() > MAX_CLIENT_NODES {
+        return Err(format!("A client can have at most {} nodes", MAX_CLIENT_NODES));
+    }
+    Ok(node_ids)
+}
+
+pub fn register(name: String, node_ids: Vec<String>) -> Result<ClientRecord, String> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("Client name must not be empty".to_string());
+    }
+    if get(&name).is_some() {
+        return Err(format!("Client {} already exists", name));
+    }
+
+    let now = ic_cdk::api::time();
+    let client = ClientRecord {
+        name,
+        node_ids: normalize_nodes(node_ids)?,
+        created_at: now,
+        updated_at: now,
+    };
+    store(client.clone());
+    Ok(client)
+}
+
+/// Replaces the nodes of a client.
+pub fn set_nodes(name: &str, node_ids: Vec<String>) -> Result<ClientRecord, String> {
+    let mut client = get(name).ok_or_else(|| format!("Client {} not found", name))?;
+    client.node_ids = normalize_nodes(node_ids)?;
+    client.updated_at = ic_cdk::api::time();
+    store(client.clone());
+    Ok(client)
+}
+
+/// Adds nodes a client was seen with, e.g. in the payments of a wallet. Unknown clients are
+/// registered.
+pub fn add_nodes(name: &str, node_ids: &[String]) {
+    let result = match get(name) {
+        Some(client) => {
+            let mut nodes = client.node_ids;
+            nodes.extend(node_ids.iter().cloned());
+            set_nodes(name, nodes).map(|_| ())
+        }
+        None => register(name.to_string(), node_ids.to_vec()).map(|_| ()),
+    };
+    if let Err(e) = result {
+        ic_cdk::println!("Cannot record the nodes of client {}: {}", name, e);
+    }
+}
+
+pub fn remove(name: &str) -> Option<ClientRecord> {
+    let client = get(name)?;
+    store(ClientRecord {
+        node_ids: vec![],
+        ..client.clone()
+    });
+    CLIENTS.with(|c| c.borrow_mut().remove(name));
+    Some(client)
+}
+
+pub fn get(name: &str) -> Option<ClientRecord> {
+    CLIENTS.with(|c| c.borrow().get(name).cloned())
+}
+
+pub fn clients_of(node_id: &str) -> Vec<String> {
+    NODE_CLIENTS.with(|n| {
+        n.borrow()
+            .get(node_id)
+            .map(|clients| clients.iter().cloned().collect())
+            .unwrap_or_default()
+    })
+}
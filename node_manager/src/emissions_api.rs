@@ -0,0 +1,237 @@
+use std::{collections::BTreeSet, fmt};
+
+use candid::CandidType;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
+    TransformFunc,
+};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::node_registry::{self, NodeMetadata};
+
+// schema versions of the emissions backend this canister can read, a bare array is version 1
+const SUPPORTED_SCHEMA_VERSIONS: &[u32] = &[1];
+// rejected rows kept per sync, the rest is only counted
+const MAX_REPORTED_REJECTIONS: usize = 100;
+const HTTP_REQUEST_CYCLES: u128 = 21_000_000_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum EmissionsFetchError {
+    // the outcall itself failed, e.g. the backend could not be reached
+    Network { code: String, message: String },
+    HttpStatus { status: u64, body: String },
+    Parse(String),
+    UnsupportedSchema(u32),
+}
+
+impl fmt::Display for EmissionsFetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmissionsFetchError::Network { code, message } => {
+                write!(f, "The http_request resulted into error. RejectionCode: {}, Error: {}", code, message)
+            }
+            EmissionsFetchError::HttpStatus { status, body } => {
+                write!(f, "The emissions backend answered with status {}: {}", status, body)
+            }
+            EmissionsFetchError::Parse(message) => write!(f, "Failed to parse emissions: {}", message),
+            EmissionsFetchError::UnsupportedSchema(version) => {
+                write!(f, "Unsupported emissions schema version {}", version)
+            }
+        }
+    }
+}
+
+/// Where the nodes are in a response and which fields hold their name and emissions. Without a
+/// path the response is either a bare array or a versioned envelope of the emissions backend.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ResponseMapping {
+    // dot separated path to the array of nodes, e.g. "data.nodes"
+    pub nodes_path: Option<String>,
+    pub name_field: String,
+    pub emissions_field: String,
+    // multiplies the emissions, e.g. 0.001 for a source that reports grams
+    pub emissions_factor: f64,
+}
+
+impl Default for ResponseMapping {
+    fn default() -> Self {
+        ResponseMapping {
+            nodes_path: None,
+            name_field: "name".to_string(),
+            emissions_field: "total_emissions".to_string(),
+            emissions_factor: 1.0,
+        }
+    }
+}
+
+/// Row of the backend response that was skipped, `index` is its position in the response.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RejectedRecord {
+    pub index: u64,
+    pub reason: String,
+}
+
+pub struct EmissionsRecord {
+    pub name: String,
+    pub total_emissions: f64,
+    pub metadata: Option<NodeMetadata>,
+}
+
+pub struct ParsedEmissions {
+    // None for responses read through a nodes_path, their layout is given by the mapping
+    pub schema_version: Option<u32>,
+    pub records: Vec<EmissionsRecord>,
+    pub rejected: Vec<RejectedRecord>,
+    pub rejected_count: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawResponse {
+    Unversioned(Vec<serde_json::Value>),
+    Versioned {
+        schema_version: u32,
+        nodes: Vec<serde_json::Value>,
+    },
+}
+
+/// GETs the emissions of every node, `transform` strips the response down to status and body.
+/// `auth` is the name and value of the header the source expects its key in.
+pub async fn fetch(url: &str, auth: Option<(&str, &str)>) -> Result<Vec<u8>, EmissionsFetchError> {
+    let mut headers = vec![HttpHeader {
+        name: "accept".to_string(),
+        value: "application/json".to_string(),
+    }];
+    if let Some((name, value)) = auth {
+        headers.push(HttpHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        method: HttpMethod::GET,
+        body: None,
+        max_response_bytes: None,
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::api::id(),
+                method: "transform".to_string(),
+            }),
+            context: vec![],
+        }),
+        headers,
+    };
+
+    let (response,) = http_request(request, HTTP_REQUEST_CYCLES)
+        .await
+        .map_err(|(code, message)| EmissionsFetchError::Network {
+            code: format!("{:?}", code),
+            message,
+        })?;
+
+    let status = u64::try_from(&response.status.0).unwrap_or(u64::MAX);
+    if !(200..300).contains(&status) {
+        return Err(EmissionsFetchError::HttpStatus {
+            status,
+            body: String::from_utf8_lossy(&response.body).chars().take(200).collect(),
+        });
+    }
+    Ok(response.body)
+}
+
+// a row has to be a node with a name and finite, non-negative emissions
+fn parse_record(value: &serde_json::Value, mapping: &ResponseMapping) -> Result<EmissionsRecord, String> {
+    let name = match &value[&mapping.name_field] {
+        serde_json::Value::String(name) => name.trim().to_string(),
+        serde_json::Value::Null => return Err(format!("Missing field {}", mapping.name_field)),
+        other => return Err(format!("Field {} is not a string: {}", mapping.name_field, other)),
+    };
+    if name.is_empty() {
+        return Err("Empty node name".to_string());
+    }
+    let emissions = match &value[&mapping.emissions_field] {
+        serde_json::Value::Number(emissions) => emissions.as_f64().unwrap_or(f64::NAN),
+        serde_json::Value::Null => return Err(format!("Missing field {}", mapping.emissions_field)),
+        other => return Err(format!("Field {} is not a number: {}", mapping.emissions_field, other)),
+    } * mapping.emissions_factor;
+    if !emissions.is_finite() || emissions < 0.0 {
+        return Err(format!("Invalid {} {}", mapping.emissions_field, emissions));
+    }
+
+    Ok(EmissionsRecord {
+        metadata: node_registry::metadata_from_backend(&name, value),
+        name,
+        total_emissions: emissions,
+    })
+}
+
+// rows of a response of the emissions backend, with the schema version it was written in
+fn backend_rows(body: &[u8]) -> Result<(Option<u32>, Vec<serde_json::Value>), EmissionsFetchError> {
+    let response: RawResponse = serde_json::from_slice(body)
+        .map_err(|e| EmissionsFetchError::Parse(e.to_string()))?;
+    let (schema_version, rows) = match response {
+        RawResponse::Unversioned(rows) => (1, rows),
+        RawResponse::Versioned {
+            schema_version,
+            nodes,
+        } => (schema_version, nodes),
+    };
+    if !SUPPORTED_SCHEMA_VERSIONS.contains(&schema_version) {
+        return Err(EmissionsFetchError::UnsupportedSchema(schema_version));
+    }
+    Ok((Some(schema_version), rows))
+}
+
+// rows at the nodes_path of a mapped response
+fn mapped_rows(body: &[u8], path: &str) -> Result<Vec<serde_json::Value>, EmissionsFetchError> {
+    let response: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|e| EmissionsFetchError::Parse(e.to_string()))?;
+    let rows = path
+        .split('.')
+        .filter(|segment| !segment.is_empty())
+        .try_fold(&response, |value, segment| value.get(segment))
+        .and_then(|value| value.as_array())
+        .ok_or_else(|| EmissionsFetchError::Parse(format!("No array of nodes at {}", path)))?;
+    Ok(rows.clone())
+}
+
+/// Parses a response of the emissions backend. Rows that do not validate are skipped and
+/// reported, only a response that cannot be read at all is an error.
+pub fn parse(body: &[u8], mapping: &ResponseMapping) -> Result<ParsedEmissions, EmissionsFetchError> {
+    let (schema_version, rows) = match &mapping.nodes_path {
+        Some(path) => (None, mapped_rows(body, path)?),
+        None => backend_rows(body)?,
+    };
+
+    let mut parsed = ParsedEmissions {
+        schema_version,
+        records: vec![],
+        rejected: vec![],
+        rejected_count: 0,
+    };
+    let mut seen = BTreeSet::new();
+    for (index, row) in rows.iter().enumerate() {
+        let result = parse_record(row, mapping).and_then(|record| {
+            if seen.insert(record.name.clone()) {
+                Ok(record)
+            } else {
+                Err(format!("Duplicate node {}", record.name))
+            }
+        });
+        match result {
+            Ok(record) => parsed.records.push(record),
+            Err(reason) => {
+                parsed.rejected_count += 1;
+                if parsed.rejected.len() < MAX_REPORTED_REJECTIONS {
+                    parsed.rejected.push(RejectedRecord {
+                        index: index as u64,
+                        reason,
+                    });
+                }
+            }
+        }
+    }
+    Ok(parsed)
+}
@@ -0,0 +1,409 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::history;
+use crate::node_registry::{self, NodeMetadata};
+
+// average PUE of data centers, used for those without a value of their own
+const DEFAULT_PUE: f64 = 1.5;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_HOUR: f64 = 3600.0;
+
+/// Average power draw of a hardware generation, in watts.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HardwareProfile {
+    pub hardware_generation: String,
+    pub power_watts: f64,
+}
+
+/// Carbon intensity of the electricity of a grid region or country, in gCO2e per kWh.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GridIntensity {
+    pub region: String,
+    pub grams_per_kwh: f64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DataCenterPue {
+    pub data_center: String,
+    pub pue: f64,
+}
+
+/// Time a node was seen up by the emissions sync, `last_seen` is the last sync that reported it.
+/// Energy and emissions are computed for the uptime as it is counted, since the inputs of the node
+/// were last complete; they are None while an input is missing.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NodeUptime {
+    pub node_id: String,
+    pub uptime_seconds: u64,
+    pub last_seen: Option<u64>,
+    pub energy_kwh: Option<f64>,
+    pub computed_emissions: Option<f64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ModelSettings {
+    pub hardware_profiles: Vec<HardwareProfile>,
+    pub grid_intensities: Vec<GridIntensity>,
+    pub data_center_pues: Vec<DataCenterPue>,
+    pub default_pue: f64,
+    pub uptimes: Vec<NodeUptime>,
+    // time of the last sync that counted uptime
+    pub last_sync: Option<u64>,
+}
+
+impl Default for ModelSettings {
+    fn default() -> Self {
+        ModelSettings {
+            hardware_profiles: vec![],
+            grid_intensities: vec![],
+            data_center_pues: vec![],
+            default_pue: DEFAULT_PUE,
+            uptimes: vec![],
+            last_sync: None,
+        }
+    }
+}
+
+// Emissions of a node computed from its inputs, next to the ones the backend reported. Inputs
+// that are not known are listed in `missing`, without them no emissions are computed. The
+// computed and fetched emissions are compared over the same days of the history, from `from` to
+// `to`, the latest days that have a computed value.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ModelledEmissions {
+    pub node_id: String,
+    pub hardware_generation: Option<String>,
+    pub power_watts: Option<f64>,
+    pub uptime_seconds: u64,
+    pub pue: f64,
+    // grid region or country the intensity was taken from
+    pub grid_region: Option<String>,
+    pub grams_per_kwh: Option<f64>,
+    // energy counted since the inputs of the node were last complete
+    pub energy_kwh: Option<f64>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub computed_emissions: Option<f64>,
+    pub fetched_emissions: Option<f64>,
+    // computed minus fetched, in kilos
+    pub difference: Option<f64>,
+    pub missing: Vec<String>,
+}
+
+#[derive(Default)]
+struct Model {
+    hardware_profiles: BTreeMap<String, f64>,
+    grid_intensities: BTreeMap<String, f64>,
+    data_center_pues: BTreeMap<String, f64>,
+    default_pue: f64,
+    uptimes: BTreeMap<String, NodeUptime>,
+    last_sync: Option<u64>,
+}
+
+// inputs of the computation a node has, see `compute`
+#[derive(Default)]
+struct Inputs {
+    hardware_generation: Option<String>,
+    power_watts: Option<f64>,
+    pue: f64,
+    grid_region: Option<String>,
+    grams_per_kwh: Option<f64>,
+}
+
+impl Inputs {
+    fn of(model: &Model, metadata: NodeMetadata) -> Inputs {
+        let grid = [metadata.grid_region, metadata.country]
+            .into_iter()
+            .flatten()
+            .find_map(|region| {
+                let grams = model.grid_intensities.get(&region).copied()?;
+                Some((region, grams))
+            });
+        Inputs {
+            power_watts: metadata
+                .hardware_generation
+                .as_ref()
+                .and_then(|generation| model.hardware_profiles.get(generation))
+                .copied(),
+            hardware_generation: metadata.hardware_generation,
+            pue: metadata
+                .data_center
+                .as_ref()
+                .and_then(|data_center| model.data_center_pues.get(data_center))
+                .copied()
+                .unwrap_or(model.default_pue),
+            grams_per_kwh: grid.as_ref().map(|(_, grams)| *grams),
+            grid_region: grid.map(|(region, _)| region),
+        }
+    }
+
+    // energy in kWh and emissions in kilos of running for some seconds
+    fn emissions(&self, seconds: u64) -> Option<(f64, f64)> {
+        let (watts, grams) = (self.power_watts?, self.grams_per_kwh?);
+        let energy_kwh = watts / 1000.0 * seconds as f64 / SECONDS_PER_HOUR * self.pue;
+        Some((energy_kwh, energy_kwh * grams / 1000.0))
+    }
+}
+
+thread_local! {
+    static MODEL: RefCell<Model> = RefCell::new(Model {
+        default_pue: DEFAULT_PUE,
+        ..Default::default()
+    });
+}
+
+pub fn model_snapshot() -> ModelSettings {
+    MODEL.with(|m| {
+        let model = m.borrow();
+        ModelSettings {
+            hardware_profiles: model
+                .hardware_profiles
+                .iter()
+                .map(|(hardware_generation, power_watts)| HardwareProfile {
+                    hardware_generation: hardware_generation.clone(),
+                    power_watts: *power_watts,
+                })
+                .collect(),
+            grid_intensities: model
+                .grid_intensities
+                .iter()
+                .map(|(region, grams_per_kwh)| GridIntensity {
+                    region: region.clone(),
+                    grams_per_kwh: *grams_per_kwh,
+                })
+                .collect(),
+            data_center_pues: model
+                .data_center_pues
+                .iter()
+                .map(|(data_center, pue)| DataCenterPue {
+                    data_center: data_center.clone(),
+                    pue: *pue,
+                })
+                .collect(),
+            default_pue: model.default_pue,
+            uptimes: model.uptimes.values().cloned().collect(),
+            last_sync: model.last_sync,
+        }
+    })
+}
+
+pub fn restore_model(settings: ModelSettings) {
+    MODEL.with(|m| {
+        *m.borrow_mut() = Model {
+            hardware_profiles: settings
+                .hardware_profiles
+                .into_iter()
+                .map(|p| (p.hardware_generation, p.power_watts))
+                .collect(),
+            grid_intensities: settings
+                .grid_intensities
+                .into_iter()
+                .map(|g| (g.region, g.grams_per_kwh))
+                .collect(),
+            data_center_pues: settings
+                .data_center_pues
+                .into_iter()
+                .map(|d| (d.data_center, d.pue))
+                .collect(),
+            default_pue: settings.default_pue,
+            // state stored before the last sync was kept, the latest node seen was seen by it
+            last_sync: settings
+                .last_sync
+                .or_else(|| settings.uptimes.iter().filter_map(|u| u.last_seen).max()),
+            uptimes: settings
+                .uptimes
+                .into_iter()
+                .map(|u| (u.node_id.clone(), u))
+                .collect(),
+        }
+    });
+}
+
+// sets or, with None, removes a value of a table of the model
+fn set_entry(
+    table: impl Fn(&mut Model) -> &mut BTreeMap<String, f64>,
+    key: &str,
+    value: Option<f64>,
+    valid: impl Fn(f64) -> bool,
+    error: &str,
+) -> Result<(), String> {
+    let key = key.trim();
+    if key.is_empty() {
+        return Err("Key must not be empty".to_string());
+    }
+    if value.is_some_and(|value| !value.is_finite() || !valid(value)) {
+        return Err(error.to_string());
+    }
+    MODEL.with(|m| {
+        let mut model = m.borrow_mut();
+        let table = table(&mut model);
+        match value {
+            Some(value) => table.insert(key.to_string(), value),
+            None => table.remove(key),
+        }
+    });
+    Ok(())
+}
+
+pub fn set_hardware_profile(hardware_generation: &str, power_watts: Option<f64>) -> Result<(), String> {
+    set_entry(
+        |model| &mut model.hardware_profiles,
+        hardware_generation,
+        power_watts,
+        |watts| watts > 0.0,
+        "Power draw must be a positive number of watts",
+    )
+}
+
+pub fn set_grid_intensity(region: &str, grams_per_kwh: Option<f64>) -> Result<(), String> {
+    set_entry(
+        |model| &mut model.grid_intensities,
+        region,
+        grams_per_kwh,
+        |grams| grams >= 0.0,
+        "Grid intensity must be a non-negative number of gCO2e per kWh",
+    )
+}
+
+pub fn set_data_center_pue(data_center: &str, pue: Option<f64>) -> Result<(), String> {
+    set_entry(
+        |model| &mut model.data_center_pues,
+        data_center,
+        pue,
+        |pue| pue >= 1.0,
+        "PUE must be at least 1",
+    )
+}
+
+pub fn set_default_pue(pue: f64) -> Result<(), String> {
+    if !pue.is_finite() || pue < 1.0 {
+        return Err("PUE must be at least 1".to_string());
+    }
+    MODEL.with(|m| m.borrow_mut().default_pue = pue);
+    Ok(())
+}
+
+/// Sets the uptime of a node, e.g. the time it ran before the sync started counting.
+pub fn set_uptime(node_id: &str, uptime_seconds: u64) {
+    MODEL.with(|m| {
+        let mut model = m.borrow_mut();
+        let uptime = model
+            .uptimes
+            .entry(node_id.to_string())
+            .or_insert_with(|| NodeUptime {
+                node_id: node_id.to_string(),
+                ..Default::default()
+            });
+        uptime.uptime_seconds = uptime_seconds;
+    });
+}
+
+/// Counts the time since the previous sync as uptime of the nodes a sync reported, and adds the
+/// energy and emissions of that time computed from the current inputs. A node that the previous
+/// sync reported as well counts as up in between however long ago it was, one that it missed
+/// counts from now on.
+pub fn record_uptime<'a>(node_ids: impl Iterator<Item = &'a str>, now: u64) {
+    MODEL.with(|m| {
+        let mut model = m.borrow_mut();
+        let last_sync = model.last_sync;
+        for node_id in node_ids {
+            let inputs = Inputs::of(&model, node_registry::get(node_id).unwrap_or_default());
+            let uptime = model
+                .uptimes
+                .entry(node_id.to_string())
+                .or_insert_with(|| NodeUptime {
+                    node_id: node_id.to_string(),
+                    ..Default::default()
+                });
+            let elapsed = match uptime.last_seen {
+                Some(last_seen) if Some(last_seen) == last_sync => {
+                    now.saturating_sub(last_seen) / NANOS_PER_SECOND
+                }
+                _ => 0,
+            };
+            uptime.uptime_seconds += elapsed;
+            // a missing input restarts the computed values once it is set
+            match inputs.emissions(elapsed) {
+                Some((energy_kwh, kilos)) => {
+                    uptime.energy_kwh = Some(uptime.energy_kwh.unwrap_or_default() + energy_kwh);
+                    uptime.computed_emissions =
+                        Some(uptime.computed_emissions.unwrap_or_default() + kilos);
+                }
+                None => {
+                    uptime.energy_kwh = None;
+                    uptime.computed_emissions = None;
+                }
+            }
+            uptime.last_seen = Some(now);
+        }
+        model.last_sync = Some(now);
+    });
+}
+
+/// Emissions computed for a node since its inputs were last complete, in kilos of CO2e.
+pub fn computed_emissions(node_id: &str) -> Option<f64> {
+    MODEL.with(|m| m.borrow().uptimes.get(node_id)?.computed_emissions)
+}
+
+/// Emissions of a node from the power draw of its hardware, its uptime, the PUE of its data
+/// center and the grid intensity of its region (or else its country), in kilos of CO2e, compared
+/// with the fetched ones over the days of the history that have both.
+pub fn compute(node_id: &str) -> ModelledEmissions {
+    let metadata = node_registry::get(node_id).unwrap_or_default();
+    let window = history::computed_window(node_id);
+    MODEL.with(|m| {
+        let model = m.borrow();
+        let inputs = Inputs::of(&model, metadata);
+        let uptime = model.uptimes.get(node_id).cloned().unwrap_or_default();
+        let mut modelled = ModelledEmissions {
+            node_id: node_id.to_string(),
+            hardware_generation: inputs.hardware_generation,
+            power_watts: inputs.power_watts,
+            uptime_seconds: uptime.uptime_seconds,
+            pue: inputs.pue,
+            grid_region: inputs.grid_region,
+            grams_per_kwh: inputs.grams_per_kwh,
+            energy_kwh: uptime.energy_kwh,
+            ..Default::default()
+        };
+        if modelled.power_watts.is_none() {
+            modelled.missing.push("power_watts".to_string());
+        }
+        if modelled.grams_per_kwh.is_none() {
+            modelled.missing.push("grams_per_kwh".to_string());
+        }
+
+        if let Some(window) = window {
+            modelled.from = Some(history::day_start(window.from_day));
+            modelled.to = Some(history::day_start(window.to_day));
+            modelled.computed_emissions = Some(window.computed.kilos());
+            modelled.fetched_emissions = Some(window.fetched.kilos());
+            modelled.difference = Some(window.computed.kilos() - window.fetched.kilos());
+        }
+        modelled
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600 * NANOS_PER_SECOND;
+
+    fn uptime(node_id: &str) -> u64 {
+        MODEL.with(|m| m.borrow().uptimes.get(node_id).map_or(0, |u| u.uptime_seconds))
+    }
+
+    #[test]
+    fn gaps_count_as_uptime_for_nodes_seen_on_both_sides() {
+        record_uptime(["a", "b"].into_iter(), HOUR);
+        // the next sync ran three hours later and did not report b
+        record_uptime(["a"].into_iter(), 4 * HOUR);
+        record_uptime(["a", "b"].into_iter(), 5 * HOUR);
+
+        assert_eq!(uptime("a"), 4 * 3600);
+        assert_eq!(uptime("b"), 0);
+    }
+}
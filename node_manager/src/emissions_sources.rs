@@ -0,0 +1,327 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::emissions_api::{self, EmissionsFetchError, ParsedEmissions, ResponseMapping};
+
+const MAX_SOURCES: usize = 5;
+const DEFAULT_SOURCE_NAME: &str = "dashboard-backend";
+const DEFAULT_SOURCE_URL: &str = "https://dashboard-backend.fly.dev/nodes/getNodeEmissions";
+const DEFAULT_AUTH_HEADER: &str = "api-key";
+const MAX_REPORTED_DIVERGENCES: usize = 100;
+
+/// Backend the emissions can be fetched from. A source without `api_key` sends the key set with
+/// `set_api_key` in its `auth_header`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EmissionsSource {
+    pub name: String,
+    pub url: String,
+    pub auth_header: Option<String>,
+    pub api_key: Option<String>,
+    pub mapping: ResponseMapping,
+}
+
+/// A source as shown to callers, without its key.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EmissionsSourceView {
+    pub name: String,
+    pub url: String,
+    pub auth_header: Option<String>,
+    pub has_api_key: bool,
+    pub mapping: ResponseMapping,
+}
+
+// sources in priority order, the first one that answers fills the cache
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SourcesConfig {
+    pub sources: Vec<EmissionsSource>,
+    // relative difference between two sources above which a sync is flagged, None skips the check
+    pub divergence_threshold: Option<f64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SourceError {
+    pub source: String,
+    pub error: EmissionsFetchError,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NodeDivergence {
+    pub node: String,
+    pub primary: f64,
+    pub secondary: f64,
+    pub relative_difference: f64,
+}
+
+// Comparison of the source that filled the cache with the next one that answered, over the nodes
+// both of them reported. Differences are relative to the larger value. The threshold is exceeded
+// when a node differs by more than it, the nodes that do are listed, largest difference first and
+// at most 100 of them.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DivergenceReport {
+    pub primary: String,
+    pub secondary: String,
+    pub common_nodes: u64,
+    pub primary_total: f64,
+    pub secondary_total: f64,
+    pub relative_difference: f64,
+    pub max_node_difference: f64,
+    pub max_node: Option<String>,
+    pub threshold: f64,
+    pub diverging_count: u64,
+    pub diverging_nodes: Vec<NodeDivergence>,
+    pub exceeded: bool,
+}
+
+/// Result of going through the sources, `emissions` is None when none of them answered.
+pub struct SourcesOutcome {
+    pub emissions: Option<(String, ParsedEmissions)>,
+    pub errors: Vec<SourceError>,
+    pub divergence: Option<DivergenceReport>,
+}
+
+impl Default for SourcesConfig {
+    fn default() -> Self {
+        SourcesConfig {
+            sources: vec![EmissionsSource {
+                name: DEFAULT_SOURCE_NAME.to_string(),
+                url: DEFAULT_SOURCE_URL.to_string(),
+                auth_header: Some(DEFAULT_AUTH_HEADER.to_string()),
+                api_key: None,
+                mapping: ResponseMapping::default(),
+            }],
+            divergence_threshold: None,
+        }
+    }
+}
+
+thread_local! {
+    static SOURCES: RefCell<SourcesConfig> = RefCell::default();
+}
+
+pub fn sources_snapshot() -> SourcesConfig {
+    SOURCES.with(|s| s.borrow().clone())
+}
+
+pub fn restore_sources(config: SourcesConfig) {
+    SOURCES.with(|s| *s.borrow_mut() = config);
+}
+
+fn validate(source: &EmissionsSource) -> Result<(), String> {
+    if source.name.trim().is_empty() {
+        return Err("Source name must not be empty".to_string());
+    }
+    if !source.url.starts_with("https://") {
+        return Err(format!("Source {} must use an https URL", source.name));
+    }
+    if source
+        .auth_header
+        .as_ref()
+        .is_some_and(|header| header.trim().is_empty())
+    {
+        return Err(format!("Source {} has an empty auth header", source.name));
+    }
+    let mapping = &source.mapping;
+    if mapping.name_field.is_empty() || mapping.emissions_field.is_empty() {
+        return Err(format!("Source {} must name its name and emissions fields", source.name));
+    }
+    if !mapping.emissions_factor.is_finite() || mapping.emissions_factor <= 0.0 {
+        return Err(format!("Source {} needs a positive emissions factor", source.name));
+    }
+    Ok(())
+}
+
+/// Replaces the sources, the first one has the highest priority.
+pub fn set_sources(sources: Vec<EmissionsSource>) -> Result<(), String> {
+    if sources.is_empty() || sources.len() > MAX_SOURCES {
+        return Err(format!("Between 1 and {} sources are required", MAX_SOURCES));
+    }
+    let mut names = BTreeMap::new();
+    for source in &sources {
+        validate(source)?;
+        if names.insert(source.name.trim(), ()).is_some() {
+            return Err(format!("Source {} is listed twice", source.name));
+        }
+    }
+    SOURCES.with(|s| s.borrow_mut().sources = sources);
+    Ok(())
+}
+
+pub fn set_divergence_threshold(threshold: Option<f64>) -> Result<(), String> {
+    if threshold.is_some_and(|threshold| !threshold.is_finite() || threshold < 0.0) {
+        return Err("Divergence threshold must be a non-negative number".to_string());
+    }
+    SOURCES.with(|s| s.borrow_mut().divergence_threshold = threshold);
+    Ok(())
+}
+
+pub fn views() -> Vec<EmissionsSourceView> {
+    SOURCES.with(|s| {
+        s.borrow()
+            .sources
+            .iter()
+            .map(|source| EmissionsSourceView {
+                name: source.name.clone(),
+                url: source.url.clone(),
+                auth_header: source.auth_header.clone(),
+                has_api_key: source.api_key.is_some(),
+                mapping: source.mapping.clone(),
+            })
+            .collect()
+    })
+}
+
+async fn fetch_source(source: &EmissionsSource, default_key: &str) -> Result<ParsedEmissions, EmissionsFetchError> {
+    // without a key the auth header is left out rather than sent empty
+    let key = source.api_key.as_deref().unwrap_or(default_key);
+    let auth = source
+        .auth_header
+        .as_deref()
+        .filter(|_| !key.is_empty())
+        .map(|header| (header, key));
+    let body = emissions_api::fetch(&source.url, auth).await?;
+    emissions_api::parse(&body, &source.mapping)
+}
+
+// relative difference of two values, 0 when both are 0
+fn relative_difference(a: f64, b: f64) -> f64 {
+    let larger = a.abs().max(b.abs());
+    if larger == 0.0 {
+        0.0
+    } else {
+        (a - b).abs() / larger
+    }
+}
+
+fn compare(
+    primary: (&str, &ParsedEmissions),
+    secondary: (&str, &ParsedEmissions),
+    threshold: f64,
+) -> DivergenceReport {
+    let secondary_emissions: BTreeMap<&str, f64> = secondary
+        .1
+        .records
+        .iter()
+        .map(|record| (record.name.as_str(), record.total_emissions))
+        .collect();
+
+    let mut report = DivergenceReport {
+        primary: primary.0.to_string(),
+        secondary: secondary.0.to_string(),
+        common_nodes: 0,
+        primary_total: 0.0,
+        secondary_total: 0.0,
+        relative_difference: 0.0,
+        max_node_difference: 0.0,
+        max_node: None,
+        threshold,
+        diverging_count: 0,
+        diverging_nodes: vec![],
+        exceeded: false,
+    };
+    for record in &primary.1.records {
+        let Some(other) = secondary_emissions.get(record.name.as_str()) else {
+            continue;
+        };
+        report.common_nodes += 1;
+        report.primary_total += record.total_emissions;
+        report.secondary_total += other;
+        let difference = relative_difference(record.total_emissions, *other);
+        if difference > report.max_node_difference {
+            report.max_node_difference = difference;
+            report.max_node = Some(record.name.clone());
+        }
+        if difference > threshold {
+            report.diverging_nodes.push(NodeDivergence {
+                node: record.name.clone(),
+                primary: record.total_emissions,
+                secondary: *other,
+                relative_difference: difference,
+            });
+        }
+    }
+    report.relative_difference = relative_difference(report.primary_total, report.secondary_total);
+    report.diverging_count = report.diverging_nodes.len() as u64;
+    report.exceeded = report.diverging_count > 0;
+    report
+        .diverging_nodes
+        .sort_by(|a, b| b.relative_difference.total_cmp(&a.relative_difference));
+    report.diverging_nodes.truncate(MAX_REPORTED_DIVERGENCES);
+    report
+}
+
+/// Tries the sources in priority order until one answers. With a divergence threshold the next
+/// source that answers is fetched as well and compared with it.
+pub async fn fetch_emissions(default_key: &str) -> SourcesOutcome {
+    let config = sources_snapshot();
+    let mut outcome = SourcesOutcome {
+        emissions: None,
+        errors: vec![],
+        divergence: None,
+    };
+
+    for source in &config.sources {
+        let result = fetch_source(source, default_key).await;
+        let parsed = match result {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                outcome.errors.push(SourceError {
+                    source: source.name.clone(),
+                    error,
+                });
+                continue;
+            }
+        };
+        match (&outcome.emissions, config.divergence_threshold) {
+            (None, Some(_)) => outcome.emissions = Some((source.name.clone(), parsed)),
+            (None, None) => {
+                outcome.emissions = Some((source.name.clone(), parsed));
+                break;
+            }
+            (Some((primary, primary_parsed)), threshold) => {
+                outcome.divergence = Some(compare(
+                    (primary, primary_parsed),
+                    (&source.name, &parsed),
+                    threshold.unwrap_or_default(),
+                ));
+                break;
+            }
+        }
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emissions_api::EmissionsRecord;
+
+    fn parsed(records: &[(&str, f64)]) -> ParsedEmissions {
+        ParsedEmissions {
+            schema_version: None,
+            records: records
+                .iter()
+                .map(|(name, total_emissions)| EmissionsRecord {
+                    name: name.to_string(),
+                    total_emissions: *total_emissions,
+                    metadata: None,
+                })
+                .collect(),
+            rejected: vec![],
+            rejected_count: 0,
+        }
+    }
+
+    #[test]
+    fn node_differences_exceed_the_threshold_when_totals_agree() {
+        let primary = parsed(&[("a", 100.0), ("b", 50.0), ("c", 10.0)]);
+        let secondary = parsed(&[("a", 50.0), ("b", 100.0), ("c", 10.5)]);
+        let report = compare(("primary", &primary), ("secondary", &secondary), 0.1);
+
+        assert!(report.relative_difference < 0.01);
+        assert!(report.exceeded);
+        let nodes: Vec<&str> = report.diverging_nodes.iter().map(|node| node.node.as_str()).collect();
+        assert_eq!(nodes, vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,128 @@
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::history;
+use crate::units::Co2e;
+
+// days of history the forecast is fitted on
+const LOOKBACK_DAYS: u64 = 90;
+const MIN_HISTORY_DAYS: u64 = 7;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ForecastTarget {
+    Client(String),
+    Subnet(String),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ForecastHorizon {
+    Month,
+    Quarter,
+    Year,
+}
+
+impl ForecastHorizon {
+    fn days(self) -> u64 {
+        match self {
+            ForecastHorizon::Month => 30,
+            ForecastHorizon::Quarter => 91,
+            ForecastHorizon::Year => 365,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ForecastMethod {
+    // least squares line through the daily emissions, extended over the horizon
+    LinearTrend,
+    // mean of the daily emissions, repeated over the horizon
+    MovingAverage,
+}
+
+/// Expected emissions of a client or subnet over the days after its latest snapshot, and the
+/// tickets that offset them.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EmissionsForecast {
+    pub target: ForecastTarget,
+    pub horizon: ForecastHorizon,
+    pub method: ForecastMethod,
+    pub node_count: u64,
+    pub history_days: u64,
+    // kg per day over the history the forecast is based on
+    pub daily_average: f64,
+    // change of the daily emissions per day, the slope of the linear trend
+    pub daily_trend: f64,
+    pub from: u64,
+    pub to: u64,
+    pub expected_emissions: Co2e,
+    pub kilos_per_ticket: f64,
+    // project or vendor the rate was set for, None for the default rate
+    pub rate_source: Option<String>,
+    pub tickets_needed: u64,
+    // price of one ticket as of the latest payment a wallet sent, None before the first one
+    pub ticket_price: Option<f64>,
+    pub ticket_budget: Option<f64>,
+}
+
+// intercept and slope of the least squares line through (day index, value)
+fn linear_fit(values: &[f64]) -> (f64, f64) {
+    let n = values.len() as f64;
+    let mean_x = (n - 1.0) / 2.0;
+    let mean_y = values.iter().sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (x, y) in values.iter().enumerate() {
+        let dx = x as f64 - mean_x;
+        covariance += dx * (y - mean_y);
+        variance += dx * dx;
+    }
+    let slope = if variance > 0.0 { covariance / variance } else { 0.0 };
+    (mean_y - slope * mean_x, slope)
+}
+
+pub fn forecast(
+    target: ForecastTarget,
+    node_ids: &[String],
+    horizon: ForecastHorizon,
+    method: ForecastMethod,
+    (kilos_per_ticket, rate_source): (f64, Option<String>),
+    ticket_price: Option<f64>,
+) -> Result<EmissionsForecast, String> {
+    let last_day = history::last_day(node_ids).ok_or("No emissions history for these nodes")?;
+    let daily = history::daily_emitted(node_ids, last_day, LOOKBACK_DAYS);
+    if (daily.len() as u64) < MIN_HISTORY_DAYS {
+        return Err(format!(
+            "At least {} days of emissions history are needed for a forecast, there are {}",
+            MIN_HISTORY_DAYS,
+            daily.len()
+        ));
+    }
+
+    let (intercept, slope) = linear_fit(&daily);
+    let daily_average = daily.iter().sum::<f64>() / daily.len() as f64;
+    let expected_kilos = match method {
+        ForecastMethod::MovingAverage => daily_average * horizon.days() as f64,
+        // emissions do not go below zero once the line does
+        ForecastMethod::LinearTrend => (daily.len() as u64..daily.len() as u64 + horizon.days())
+            .map(|x| (intercept + slope * x as f64).max(0.0))
+            .sum(),
+    };
+    let tickets_needed = (expected_kilos / kilos_per_ticket).ceil() as u64;
+
+    Ok(EmissionsForecast {
+        target,
+        horizon,
+        method,
+        node_count: node_ids.len() as u64,
+        history_days: daily.len() as u64,
+        daily_average,
+        daily_trend: slope,
+        from: history::day_start(last_day + 1),
+        to: history::day_start(last_day + 1 + horizon.days()),
+        expected_emissions: Co2e::from_kilos(expected_kilos),
+        kilos_per_ticket,
+        rate_source,
+        tickets_needed,
+        ticket_price,
+        ticket_budget: ticket_price.map(|price| price * tickets_needed as f64),
+    })
+}
@@ -0,0 +1,319 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::units::Co2e;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Days of snapshots that are kept. Every sync records every node, so without a limit the history
+// grows by a row per node and day and the upgrade runs out of instructions encoding it.
+const RETENTION_DAYS: u64 = 400;
+
+// emitted, offset and computed emissions of a node at the end of a day
+type DayTotals = (Co2e, Co2e, Option<Co2e>);
+
+/// Emissions of a node as of the end of a day, days are counted from the unix epoch.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DailySnapshot {
+    pub node_id: String,
+    pub day: u64,
+    pub emitted: Co2e,
+    pub offset: Co2e,
+    // emissions the model computed for the node so far, see emissions_model
+    pub computed: Option<Co2e>,
+}
+
+// snapshot as stored before the history was fixed-point, in kilos
+#[derive(CandidType, Deserialize)]
+pub struct LegacyDailySnapshot {
+    pub node_id: String,
+    pub day: u64,
+    pub emitted: f64,
+    pub offset: f64,
+    pub computed: Option<f64>,
+}
+
+impl LegacyDailySnapshot {
+    pub fn migrate(self) -> DailySnapshot {
+        DailySnapshot {
+            node_id: self.node_id,
+            day: self.day,
+            emitted: Co2e::from_kilos(self.emitted),
+            offset: Co2e::from_kilos(self.offset),
+            computed: self.computed.map(Co2e::from_kilos),
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Granularity {
+    Day,
+    // weeks start on Monday
+    Week,
+    Month,
+}
+
+// Values at the end of a period, the last snapshot within it. A client point sums the last
+// snapshot of each of its nodes.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HistoryPoint {
+    pub period_start: u64,
+    pub emitted: Co2e,
+    pub offset: Co2e,
+    pub net: Co2e,
+}
+
+/// Growth of the fetched and computed emissions of a node between two days.
+pub struct ComputedWindow {
+    pub from_day: u64,
+    pub to_day: u64,
+    pub fetched: Co2e,
+    pub computed: Co2e,
+}
+
+thread_local! {
+    static HISTORY: RefCell<BTreeMap<(String, u64), DayTotals>> = RefCell::default();
+    // day the snapshots older than the retention were last dropped on
+    static TRIMMED_ON: RefCell<Option<u64>> = const { RefCell::new(None) };
+}
+
+pub fn history_snapshot() -> Vec<DailySnapshot> {
+    HISTORY.with(|h| {
+        h.borrow()
+            .iter()
+            .map(|((node_id, day), (emitted, offset, computed))| DailySnapshot {
+                node_id: node_id.clone(),
+                day: *day,
+                emitted: *emitted,
+                offset: *offset,
+                computed: *computed,
+            })
+            .collect()
+    })
+}
+
+pub fn restore_history(snapshots: Vec<DailySnapshot>) {
+    HISTORY.with(|h| {
+        *h.borrow_mut() = snapshots
+            .into_iter()
+            .map(|s| ((s.node_id, s.day), (s.emitted, s.offset, s.computed)))
+            .collect()
+    });
+}
+
+/// Stores the current values of a node as the snapshot of today, later calls on the same day
+/// overwrite it.
+pub fn record(node_id: &str, emitted: Co2e, offset: Co2e, computed: Option<Co2e>, now: u64) {
+    HISTORY.with(|h| {
+        h.borrow_mut().insert(
+            (node_id.to_string(), now / NANOS_PER_DAY),
+            (emitted, offset, computed),
+        )
+    });
+}
+
+/// Drops the snapshots older than RETENTION_DAYS, once a day.
+pub fn trim(now: u64) {
+    let today = now / NANOS_PER_DAY;
+    if TRIMMED_ON.with(|t| t.replace(Some(today))) == Some(today) {
+        return;
+    }
+    let oldest = today.saturating_sub(RETENTION_DAYS);
+    HISTORY.with(|h| h.borrow_mut().retain(|(_, day), _| *day >= oldest));
+}
+
+// (year, month) of a day since the unix epoch, from Howard Hinnant's civil_from_days
+fn year_month(day: u64) -> (i64, u64) {
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month)
+}
+
+// first day of the month, Hinnant's days_from_civil for day 1
+fn first_of_month(year: i64, month: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146_097 + doe - 719_468) as u64
+}
+
+// first day of the period a day belongs to
+fn period_start(day: u64, granularity: Granularity) -> u64 {
+    match granularity {
+        Granularity::Day => day,
+        // the epoch was a Thursday
+        Granularity::Week => day.saturating_sub((day + 3) % 7),
+        Granularity::Month => {
+            let (year, month) = year_month(day);
+            first_of_month(year, month)
+        }
+    }
+}
+
+/// History of some nodes between two timestamps (inclusive), one point per period, oldest first.
+pub fn points(
+    node_ids: &[String],
+    from: Option<u64>,
+    to: Option<u64>,
+    granularity: Granularity,
+) -> Vec<HistoryPoint> {
+    let from_day = from.map_or(0, |from| from / NANOS_PER_DAY);
+    let to_day = to.map_or(u64::MAX, |to| to / NANOS_PER_DAY);
+    if from_day > to_day {
+        return vec![];
+    }
+
+    // last snapshot of every node per period
+    let mut closing: BTreeMap<(u64, &str), DayTotals> = BTreeMap::new();
+    HISTORY.with(|h| {
+        let history = h.borrow();
+        for node_id in node_ids {
+            let range = (node_id.clone(), from_day)..=(node_id.clone(), to_day);
+            for ((_, day), values) in history.range(range) {
+                closing.insert((period_start(*day, granularity), node_id.as_str()), *values);
+            }
+        }
+    });
+
+    let mut points: BTreeMap<u64, HistoryPoint> = BTreeMap::new();
+    for ((period, _), (emitted, offset, _)) in closing {
+        let point = points.entry(period).or_insert_with(|| HistoryPoint {
+            period_start: period * NANOS_PER_DAY,
+            ..Default::default()
+        });
+        point.emitted += emitted;
+        point.offset += offset;
+        point.net += emitted - offset;
+    }
+    points.into_values().collect()
+}
+
+/// What some nodes emitted between two timestamps (inclusive): the last snapshot up to `to` minus
+/// the last one before `from`. A node first seen within the period counts from its first snapshot.
+pub fn emitted_between(node_ids: &[String], from: Option<u64>, to: Option<u64>) -> Co2e {
+    let from_day = from.map_or(0, |from| from / NANOS_PER_DAY);
+    let to_day = to.map_or(u64::MAX, |to| to / NANOS_PER_DAY);
+    if from_day > to_day {
+        return Co2e::ZERO;
+    }
+
+    HISTORY.with(|h| {
+        let history = h.borrow();
+        node_ids
+            .iter()
+            .map(|node_id| {
+                let mut period = history.range((node_id.clone(), from_day)..=(node_id.clone(), to_day));
+                let Some((_, (closing, _, _))) = period.next_back() else {
+                    return Co2e::ZERO;
+                };
+                let opening = match from {
+                    None => Co2e::ZERO,
+                    Some(_) => history
+                        .range((node_id.clone(), 0)..(node_id.clone(), from_day))
+                        .next_back()
+                        .or_else(|| history.range((node_id.clone(), from_day)..).next())
+                        .map_or(Co2e::ZERO, |(_, (emitted, _, _))| *emitted),
+                };
+                *closing - opening
+            })
+            .sum()
+    })
+}
+
+/// What some nodes emitted per day over the `days` days up to and including `to_day`, oldest
+/// first. The growth between two snapshots of a node is spread evenly over the days between them,
+/// days before the first two snapshots of any node are left out.
+pub fn daily_emitted(node_ids: &[String], to_day: u64, days: u64) -> Vec<f64> {
+    let from_day = (to_day + 1).saturating_sub(days);
+    let mut daily: BTreeMap<u64, f64> = BTreeMap::new();
+    HISTORY.with(|h| {
+        let history = h.borrow();
+        for node_id in node_ids {
+            // the last snapshot before the window is the opening value
+            let opening = history
+                .range((node_id.clone(), 0)..(node_id.clone(), from_day))
+                .next_back()
+                .map(|((_, day), (emitted, _, _))| (*day, emitted.kilos()));
+            let window = history
+                .range((node_id.clone(), from_day)..=(node_id.clone(), to_day))
+                .map(|((_, day), (emitted, _, _))| (*day, emitted.kilos()));
+            let mut previous: Option<(u64, f64)> = None;
+            for (day, emitted) in opening.into_iter().chain(window) {
+                if let Some((previous_day, previous_emitted)) = previous {
+                    let gap = day - previous_day;
+                    let per_day = (emitted - previous_emitted).max(0.0) / gap as f64;
+                    for d in (previous_day + 1).max(from_day)..=day {
+                        *daily.entry(d).or_default() += per_day;
+                    }
+                }
+                previous = Some((day, emitted));
+            }
+        }
+    });
+    let Some(first_day) = daily.keys().next().copied() else {
+        return vec![];
+    };
+    (first_day..=to_day)
+        .map(|day| daily.get(&day).copied().unwrap_or_default())
+        .collect()
+}
+
+/// What a node emitted according to the fetched and the computed emissions over the same days:
+/// from the first to the last snapshot of its latest days with a computed value. A missing
+/// computed value or a smaller one than the day after, where the computation restarted, ends them.
+pub fn computed_window(node_id: &str) -> Option<ComputedWindow> {
+    HISTORY.with(|h| {
+        let history = h.borrow();
+        let mut snapshots = history
+            .range((node_id.to_string(), 0)..=(node_id.to_string(), u64::MAX))
+            .rev();
+        let ((_, to_day), (closing_emitted, _, closing_computed)) = snapshots.next()?;
+        let closing_computed = (*closing_computed)?;
+        let (mut from_day, mut opening_emitted, mut opening_computed) =
+            (*to_day, *closing_emitted, closing_computed);
+        for ((_, day), (emitted, _, computed)) in snapshots {
+            match computed {
+                Some(computed) if *computed <= opening_computed => {
+                    (from_day, opening_emitted, opening_computed) = (*day, *emitted, *computed);
+                }
+                _ => break,
+            }
+        }
+        Some(ComputedWindow {
+            from_day,
+            to_day: *to_day,
+            fetched: *closing_emitted - opening_emitted,
+            computed: closing_computed - opening_computed,
+        })
+    })
+}
+
+/// Day of the latest snapshot of some nodes, None when there is none.
+pub fn last_day(node_ids: &[String]) -> Option<u64> {
+    HISTORY.with(|h| {
+        let history = h.borrow();
+        node_ids
+            .iter()
+            .filter_map(|node_id| {
+                history
+                    .range((node_id.clone(), 0)..=(node_id.clone(), u64::MAX))
+                    .next_back()
+                    .map(|((_, day), _)| *day)
+            })
+            .max()
+    })
+}
+
+pub fn day_start(day: u64) -> u64 {
+    day * NANOS_PER_DAY
+}
@@ -1 +1,18 @@
+mod aggregation;
+mod allocation;
+mod balance;
+mod certification;
+mod clients;
+mod emissions_api;
+mod emissions_model;
+mod emissions_sources;
+mod forecast;
+mod history;
+mod node_listing;
+mod node_registry;
+mod offset_ledger;
+mod projects;
+mod report;
+mod sampling;
+mod units;
 mod node_manager;
@@ -0,0 +1,107 @@
+use std::cmp::Reverse;
+
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::aggregation::NodeEmissions;
+use crate::node_registry;
+use crate::units::Co2e;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum NodeSortKey {
+    Total,
+    Offset,
+    Net,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct NodeSort {
+    pub key: NodeSortKey,
+    pub descending: bool,
+}
+
+// Filters of a node listing, every filter that is set has to match. Subnet and provider come from
+// the node registry.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NodeFilter {
+    pub subnet_id: Option<String>,
+    pub node_provider: Option<String>,
+    // true for nodes that were offset and have no emissions left, false for the others
+    pub fully_offset: Option<bool>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NodeListing {
+    pub node_id: String,
+    pub subnet_id: Option<String>,
+    pub node_provider: Option<String>,
+    pub total_emissions: Co2e,
+    pub offset_emissions: Co2e,
+    pub net_emissions: Co2e,
+    pub fully_offset: bool,
+}
+
+/// One page of a node listing. `next_start` is the `start` of the next page, None on the last one.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NodePage {
+    pub nodes: Vec<NodeListing>,
+    pub total_count: u64,
+    pub next_start: Option<u64>,
+}
+
+fn listing((node_id, net_emissions, offset_emissions): NodeEmissions) -> NodeListing {
+    let metadata = node_registry::get(&node_id).unwrap_or_default();
+    NodeListing {
+        node_id,
+        subnet_id: metadata.subnet_id,
+        node_provider: metadata.node_provider,
+        total_emissions: net_emissions + offset_emissions,
+        offset_emissions,
+        net_emissions,
+        fully_offset: net_emissions.is_zero() && !offset_emissions.is_zero(),
+    }
+}
+
+fn matches(node: &NodeListing, filter: &NodeFilter) -> bool {
+    filter.subnet_id.as_ref().is_none_or(|subnet| node.subnet_id.as_ref() == Some(subnet))
+        && filter
+            .node_provider
+            .as_ref()
+            .is_none_or(|provider| node.node_provider.as_ref() == Some(provider))
+        && filter.fully_offset.is_none_or(|fully_offset| node.fully_offset == fully_offset)
+}
+
+/// Filters and sorts the nodes and returns `limit` of them from position `start` on. Nodes are
+/// ordered by id without a sort and among equal values, so pages do not overlap.
+pub fn page(
+    nodes: impl Iterator<Item = NodeEmissions>,
+    filter: &NodeFilter,
+    sort: Option<NodeSort>,
+    start: u64,
+    limit: u64,
+) -> NodePage {
+    let mut nodes: Vec<NodeListing> = nodes.map(listing).filter(|node| matches(node, filter)).collect();
+    nodes.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+    if let Some(sort) = sort {
+        let value = |node: &NodeListing| match sort.key {
+            NodeSortKey::Total => node.total_emissions,
+            NodeSortKey::Offset => node.offset_emissions,
+            NodeSortKey::Net => node.net_emissions,
+        };
+        // the sort is stable, equal values stay ordered by id
+        if sort.descending {
+            nodes.sort_by_key(|node| Reverse(value(node)));
+        } else {
+            nodes.sort_by_key(value);
+        }
+    }
+
+    let total_count = nodes.len() as u64;
+    let nodes: Vec<NodeListing> = nodes.into_iter().skip(start as usize).take(limit as usize).collect();
+    let end = start.saturating_add(nodes.len() as u64);
+    NodePage {
+        nodes,
+        total_count,
+        next_start: (end < total_count).then_some(end),
+    }
+}
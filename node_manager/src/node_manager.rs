@@ -1,14 +1,15 @@
-use std::{cell::RefCell, collections::HashSet};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    time::Duration,
+};
 
 use candid::{ Principal, Nat};
 use ic_cdk::api::call;
-use ic_cdk::api::management_canister::http_request::TransformFunc;
-use ic_cdk::api::management_canister::http_request::{
-    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
-    TransformContext,
-};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpResponse, TransformArgs};
 use ic_cdk::caller;
-use ic_cdk::{export_candid, query, update};
+use ic_cdk::{export_candid, init, post_upgrade, pre_upgrade, query, update};
 // use ic_cdk::api::call::call;
 use candid::CandidType;
 use icrc_ledger_types::icrc1::account::Account;
@@ -16,11 +17,55 @@ use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromErro
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 
+use crate::aggregation::{self, EmissionsGrouping, EmissionsSummary, NodeEmissions};
+use crate::allocation::{self, AllocationSettings, AllocationStrategy};
+use crate::balance::{self, CarbonBalance};
+use crate::certification::{certify_all, certify_node, node_witness, nodes_witness};
+use crate::clients::{self, ClientRecord};
+use crate::emissions_api::{EmissionsFetchError, ParsedEmissions, RejectedRecord};
+use crate::emissions_model::{self, ModelSettings, ModelledEmissions};
+use crate::emissions_sources::{
+    self, DivergenceReport, EmissionsSource, EmissionsSourceView, SourceError, SourcesConfig,
+};
+use crate::forecast::{self, EmissionsForecast, ForecastHorizon, ForecastMethod, ForecastTarget};
+use crate::history::{self, DailySnapshot, Granularity, HistoryPoint, LegacyDailySnapshot};
+use crate::node_listing::{self, NodeFilter, NodePage, NodeSort};
+use crate::node_registry::{self, NodeMetadata};
+use crate::offset_ledger::{
+    self, LedgerEntry, LedgerEntryKind, LegacyLedgerEntry, NodeOffset, PaymentKey, SelectionRecord,
+};
+use crate::projects::{self, LegacyProject, Project, ProjectFunding, ProjectInput, ProjectStatus};
+use crate::report::{self, EsgReport, ReportFormat};
+use crate::sampling::{candidates_hash, to_hex, weighted_sample};
+use crate::units::{apportion, Co2e};
+
 #[derive(CandidType, Serialize, Deserialize, Clone)]
 struct Node {
+    name: String,
+    total_emissions: Co2e,
+    offset_emissions: Co2e,
+    // emissions the model computes for the node when the sync fetched it, see emissions_model
+    computed_emissions: Option<Co2e>,
+}
+
+// node as stored before emissions were fixed-point, in kilos
+#[derive(CandidType, Deserialize)]
+struct LegacyNode {
     name: String,
     total_emissions: f64,
     offset_emissions: f64,
+    computed_emissions: Option<f64>,
+}
+
+impl LegacyNode {
+    fn migrate(self) -> Node {
+        Node {
+            name: self.name,
+            total_emissions: Co2e::from_kilos(self.total_emissions),
+            offset_emissions: Co2e::from_kilos(self.offset_emissions),
+            computed_emissions: self.computed_emissions.map(Co2e::from_kilos),
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize)]
@@ -35,27 +80,396 @@ struct SimpleClient {
     node_ids: Vec<String>,
 }
 
+// payment as recorded by the esg_wallet canister
 #[derive(CandidType, Deserialize)]
 struct Payment {
+    pub block_height: Nat,
+    pub payer: String,
+    pub ticket_count: f64,
+    pub ticket_price: f64,
+    pub node_id: Option<String>,
+    pub cawa_url: String,
+    pub client: Option<String>,
+    pub batch_id: Option<u64>,
+    // CO2e the wallet bought with the payment, payments without it are converted by ticket
+    pub co2e: Option<Co2e>,
+    pub project: Option<String>,
+    pub vendor: Option<String>,
+}
+
+impl Payment {
+    fn key(&self) -> PaymentKey {
+        PaymentKey {
+            block_height: u64::try_from(&self.block_height.0).unwrap_or(u64::MAX),
+            node_id: self.node_id.clone(),
+            client: self.client.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AppliedPayment {
     pub block_height: u64,
     pub payer: String,
-    pub ticket_count: u64,
-    pub ticket_price: u64,
-    pub contribution_id: String,
+    pub co2e: Co2e,
+    pub allocations: Vec<NodeOffset>,
+    // CO2e the nodes had no emissions left for
+    pub unallocated: Co2e,
+    pub ledger_entry: u64,
+    // hex encoded raw_rand seed, sample size and candidates hash, when the nodes were picked at
+    // random
+    pub random_seed: Option<String>,
+    pub random_sample_size: Option<u64>,
+    pub candidates_hash: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SkippedPayment {
+    pub block_height: u64,
+    pub reason: String,
+}
+
+// nodes picked by select_random_nodes, the same seed and candidates give the same nodes. The
+// candidates hash identifies the nodes and weights the sample was drawn from.
+#[derive(CandidType, Serialize, Deserialize)]
+struct RandomSelection {
+    pub seed: Vec<u8>,
+    pub sample_size: u64,
+    pub candidates_hash: Vec<u8>,
+    pub nodes: Vec<Node>,
+}
+
+impl RandomSelection {
+    fn record(&self) -> SelectionRecord {
+        SelectionRecord {
+            seed: self.seed.clone(),
+            sample_size: self.sample_size,
+            candidates_hash: self.candidates_hash.clone(),
+        }
+    }
+}
+
+// last emissions fetched from the backend, every offset is computed from this snapshot
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct EmissionsCache {
+    pub nodes: Vec<Node>,
+    pub fetched_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyEmissionsCache {
+    pub nodes: Vec<LegacyNode>,
+    pub fetched_at: Option<u64>,
+}
+
+impl LegacyEmissionsCache {
+    fn migrate(self) -> EmissionsCache {
+        EmissionsCache {
+            nodes: self.nodes.into_iter().map(LegacyNode::migrate).collect(),
+            fetched_at: self.fetched_at,
+        }
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
-struct Project {
-    pub id: Vec<String>,
-    pub name: String,
-    pub icon: Option<String>,
+struct EmissionsSyncStatus {
+    pub last_synced_at: Option<u64>,
+    pub cache_age_seconds: Option<u64>,
+    pub node_count: u64,
+    pub last_attempt_at: Option<u64>,
+    pub last_error: Option<EmissionsFetchError>,
+    pub schema_version: Option<u32>,
+    // rows of the last successful sync that were skipped, at most 100 are listed
+    pub rejected_count: u64,
+    pub rejected_records: Vec<RejectedRecord>,
+    pub sync_interval_seconds: u64,
+    // source the cache was filled from, and the sources that failed before or after it
+    pub source: Option<String>,
+    pub source_errors: Vec<SourceError>,
+    pub divergence: Option<DivergenceReport>,
+}
+
+#[derive(Clone, Default)]
+struct SyncAttempt {
+    pub at: Option<u64>,
+    pub error: Option<EmissionsFetchError>,
+    pub schema_version: Option<u32>,
+    pub rejected_count: u64,
+    pub rejected_records: Vec<RejectedRecord>,
+    pub source: Option<String>,
+    pub source_errors: Vec<SourceError>,
+    pub divergence: Option<DivergenceReport>,
+}
+
+// offsets of a client, per node and summed over its nodes
+#[derive(CandidType, Serialize, Deserialize)]
+struct ClientOffsetEmissions {
+    pub client: String,
+    pub nodes: Vec<Node>,
+    pub total_emissions: Co2e,
+    pub offset_emissions: Co2e,
+    pub net_emissions: Co2e,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct CertifiedNodes {
+    pub nodes: Vec<Node>,
+    pub certificate: Vec<u8>,
+    pub witness: Vec<u8>,
 }
 
 thread_local! {
     static API_KEY: RefCell<String> = RefCell::new(String::new());
     static AUTHORIZED_PRINCIPALS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
     static NODES: RefCell<Vec<Node>> = RefCell::new(Vec::new());
-    static PROJECTS: RefCell<Vec<Project>> = RefCell::new(vec![]);
+    static OFFSET_RATES: RefCell<BTreeMap<String, f64>> = RefCell::default();
+    static RANDOM_SAMPLE_SIZE: RefCell<u64> = const { RefCell::new(DEFAULT_RANDOM_SAMPLE_SIZE) };
+    static EMISSIONS_CACHE: RefCell<EmissionsCache> = RefCell::default();
+    // outcome of the last sync attempt, successful or not
+    static LAST_SYNC_ATTEMPT: RefCell<SyncAttempt> = RefCell::default();
+    static SYNC_IN_PROGRESS: RefCell<bool> = const { RefCell::new(false) };
+    // ticket price of the latest payment a wallet sent
+    static TICKET_PRICE: RefCell<Option<f64>> = const { RefCell::new(None) };
+    static WALLET_CANISTERS: RefCell<BTreeSet<Principal>> = RefCell::default();
+}
+
+// Cawa contributions are made in kilos, one ticket is one kilo of CO2e
+const DEFAULT_KILOS_PER_TICKET: f64 = 1.0;
+const MAX_LEDGER_PAGE: u64 = 1000;
+const MAX_RANKING_SIZE: u64 = 100;
+const DEFAULT_NODE_PAGE: u64 = 100;
+const MAX_NODE_PAGE: u64 = 1000;
+// nodes an offset of a client without nodes is spread over
+const DEFAULT_RANDOM_SAMPLE_SIZE: u64 = 5;
+// how often the emissions are fetched from the backend
+const EMISSIONS_SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+fn is_authorized() -> bool {
+    let caller = caller();
+    AUTHORIZED_PRINCIPALS.with(|p| {
+        let authorized_principals = p.borrow();
+        authorized_principals.is_empty() || authorized_principals.contains(&caller)
+    })
+}
+
+// Schema of the state written to stable memory on upgrade. Optional fields can be added to the
+// current version in place, any other change needs a new variant plus a step in `into_current`.
+#[derive(CandidType, Deserialize)]
+enum StableState {
+    V1(StableStateV1),
+    V2(StableStateV2),
+    V3(StableStateV3),
+    V4(StableStateV4),
+}
+
+#[derive(CandidType, Deserialize)]
+struct StableStateV1 {
+    api_key: String,
+    authorized_principals: Vec<Principal>,
+    nodes: Vec<LegacyNode>,
+    projects: Vec<LegacyProject>,
+    offset_rates: Option<Vec<(String, f64)>>,
+    offset_ledger: Option<Vec<LegacyLedgerEntry>>,
+    allocation: Option<AllocationSettings>,
+    random_sample_size: Option<u64>,
+    node_registry: Option<Vec<NodeMetadata>>,
+    emissions_cache: Option<LegacyEmissionsCache>,
+    history: Option<Vec<LegacyDailySnapshot>>,
+    clients: Option<Vec<ClientRecord>>,
+}
+
+// V2 gives projects their own id and metadata
+#[derive(CandidType, Deserialize)]
+struct StableStateV2 {
+    api_key: String,
+    authorized_principals: Vec<Principal>,
+    nodes: Vec<LegacyNode>,
+    projects: Vec<Project>,
+    offset_rates: Option<Vec<(String, f64)>>,
+    offset_ledger: Option<Vec<LegacyLedgerEntry>>,
+    allocation: Option<AllocationSettings>,
+    random_sample_size: Option<u64>,
+    node_registry: Option<Vec<NodeMetadata>>,
+    emissions_cache: Option<LegacyEmissionsCache>,
+    history: Option<Vec<LegacyDailySnapshot>>,
+    clients: Option<Vec<ClientRecord>>,
+    emissions_sources: Option<SourcesConfig>,
+    emissions_model: Option<ModelSettings>,
+}
+
+// V3 stores the emissions of nodes as whole grams
+#[derive(CandidType, Deserialize)]
+struct StableStateV3 {
+    api_key: String,
+    authorized_principals: Vec<Principal>,
+    nodes: Vec<Node>,
+    projects: Vec<Project>,
+    offset_rates: Option<Vec<(String, f64)>>,
+    offset_ledger: Option<Vec<LegacyLedgerEntry>>,
+    allocation: Option<AllocationSettings>,
+    random_sample_size: Option<u64>,
+    node_registry: Option<Vec<NodeMetadata>>,
+    emissions_cache: Option<EmissionsCache>,
+    history: Option<Vec<LegacyDailySnapshot>>,
+    clients: Option<Vec<ClientRecord>>,
+    emissions_sources: Option<SourcesConfig>,
+    emissions_model: Option<ModelSettings>,
+    ticket_price: Option<f64>,
+    wallet_canisters: Option<Vec<Principal>>,
+}
+
+// V4 stores the offset ledger and the emissions history as whole grams
+#[derive(CandidType, Deserialize, Default)]
+struct StableStateV4 {
+    api_key: String,
+    authorized_principals: Vec<Principal>,
+    nodes: Vec<Node>,
+    projects: Vec<Project>,
+    offset_rates: Option<Vec<(String, f64)>>,
+    offset_ledger: Option<Vec<LedgerEntry>>,
+    allocation: Option<AllocationSettings>,
+    random_sample_size: Option<u64>,
+    node_registry: Option<Vec<NodeMetadata>>,
+    emissions_cache: Option<EmissionsCache>,
+    history: Option<Vec<DailySnapshot>>,
+    clients: Option<Vec<ClientRecord>>,
+    emissions_sources: Option<SourcesConfig>,
+    emissions_model: Option<ModelSettings>,
+    ticket_price: Option<f64>,
+    wallet_canisters: Option<Vec<Principal>>,
+}
+
+impl StableState {
+    // migrates a state written by an older version to the current schema, one version at a time
+    fn into_current(self) -> StableStateV4 {
+        match self {
+            StableState::V1(state) => StableState::V2(StableStateV2 {
+                api_key: state.api_key,
+                authorized_principals: state.authorized_principals,
+                nodes: state.nodes,
+                projects: state.projects.into_iter().map(LegacyProject::migrate).collect(),
+                offset_rates: state.offset_rates,
+                offset_ledger: state.offset_ledger,
+                allocation: state.allocation,
+                random_sample_size: state.random_sample_size,
+                node_registry: state.node_registry,
+                emissions_cache: state.emissions_cache,
+                history: state.history,
+                clients: state.clients,
+                emissions_sources: None,
+                emissions_model: None,
+            })
+            .into_current(),
+            StableState::V2(state) => StableState::V3(StableStateV3 {
+                api_key: state.api_key,
+                authorized_principals: state.authorized_principals,
+                nodes: state.nodes.into_iter().map(LegacyNode::migrate).collect(),
+                projects: state.projects,
+                offset_rates: state.offset_rates,
+                offset_ledger: state.offset_ledger,
+                allocation: state.allocation,
+                random_sample_size: state.random_sample_size,
+                node_registry: state.node_registry,
+                emissions_cache: state.emissions_cache.map(LegacyEmissionsCache::migrate),
+                history: state.history,
+                clients: state.clients,
+                emissions_sources: state.emissions_sources,
+                emissions_model: state.emissions_model,
+                ticket_price: None,
+                wallet_canisters: None,
+            })
+            .into_current(),
+            StableState::V3(state) => StableStateV4 {
+                api_key: state.api_key,
+                authorized_principals: state.authorized_principals,
+                nodes: state.nodes,
+                projects: state.projects,
+                offset_rates: state.offset_rates,
+                offset_ledger: state
+                    .offset_ledger
+                    .map(|ledger| ledger.into_iter().map(LegacyLedgerEntry::migrate).collect()),
+                allocation: state.allocation,
+                random_sample_size: state.random_sample_size,
+                node_registry: state.node_registry,
+                emissions_cache: state.emissions_cache,
+                history: state
+                    .history
+                    .map(|history| history.into_iter().map(LegacyDailySnapshot::migrate).collect()),
+                clients: state.clients,
+                emissions_sources: state.emissions_sources,
+                emissions_model: state.emissions_model,
+                ticket_price: state.ticket_price,
+                wallet_canisters: state.wallet_canisters,
+            },
+            StableState::V4(state) => state,
+        }
+    }
+}
+
+#[init]
+fn init() {
+    start_emissions_sync();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let state = StableStateV4 {
+        api_key: API_KEY.with(|k| k.borrow().clone()),
+        authorized_principals: AUTHORIZED_PRINCIPALS.with(|p| p.borrow().iter().cloned().collect()),
+        nodes: NODES.with(|n| n.borrow().clone()),
+        projects: projects::projects_snapshot(),
+        offset_rates: Some(get_kilos_per_ticket()),
+        offset_ledger: Some(offset_ledger::ledger_snapshot()),
+        allocation: Some(allocation::settings_snapshot()),
+        random_sample_size: Some(RANDOM_SAMPLE_SIZE.with(|s| *s.borrow())),
+        node_registry: Some(node_registry::registry_snapshot()),
+        emissions_cache: Some(EMISSIONS_CACHE.with(|c| c.borrow().clone())),
+        history: Some(history::history_snapshot()),
+        clients: Some(clients::clients_snapshot()),
+        emissions_sources: Some(emissions_sources::sources_snapshot()),
+        emissions_model: Some(emissions_model::model_snapshot()),
+        ticket_price: TICKET_PRICE.with(|p| *p.borrow()),
+        wallet_canisters: Some(WALLET_CANISTERS.with(|w| w.borrow().iter().cloned().collect())),
+    };
+    ic_cdk::storage::stable_save((StableState::V4(state),)).unwrap();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // releases before the stable schema never wrote to stable memory, they start out empty. A
+    // state that does not decode traps, so the upgrade fails instead of dropping the state.
+    let state = if ic_cdk::api::stable::stable64_size() == 0 {
+        StableStateV4::default()
+    } else {
+        match ic_cdk::storage::stable_restore::<(StableState,)>() {
+            Ok((state,)) => state.into_current(),
+            Err(e) => ic_cdk::trap(&format!("Failed to restore the stable state: {}", e)),
+        }
+    };
+
+    API_KEY.with(|k| *k.borrow_mut() = state.api_key);
+    AUTHORIZED_PRINCIPALS.with(|p| *p.borrow_mut() = state.authorized_principals.into_iter().collect());
+    certify_all(state.nodes.iter().map(|node| (node.name.as_str(), node)));
+    NODES.with(|n| *n.borrow_mut() = state.nodes);
+    projects::restore_projects(state.projects);
+    OFFSET_RATES.with(|r| *r.borrow_mut() = state.offset_rates.unwrap_or_default().into_iter().collect());
+    offset_ledger::restore_ledger(state.offset_ledger.unwrap_or_default());
+    allocation::restore_settings(state.allocation.unwrap_or_default());
+    RANDOM_SAMPLE_SIZE.with(|s| {
+        *s.borrow_mut() = state.random_sample_size.unwrap_or(DEFAULT_RANDOM_SAMPLE_SIZE)
+    });
+    node_registry::restore_registry(state.node_registry.unwrap_or_default());
+    EMISSIONS_CACHE.with(|c| *c.borrow_mut() = state.emissions_cache.unwrap_or_default());
+    history::restore_history(state.history.unwrap_or_default());
+    clients::restore_clients(state.clients.unwrap_or_default());
+    emissions_sources::restore_sources(state.emissions_sources.unwrap_or_default());
+    emissions_model::restore_model(state.emissions_model.unwrap_or_default());
+    TICKET_PRICE.with(|p| *p.borrow_mut() = state.ticket_price);
+    WALLET_CANISTERS.with(|w| {
+        *w.borrow_mut() = state.wallet_canisters.unwrap_or_default().into_iter().collect()
+    });
+    start_emissions_sync();
 }
 
 #[update]
@@ -125,83 +539,377 @@ fn transform(raw: TransformArgs) -> HttpResponse {
     res
 }
 
-// query api to get all nodes plus their emissions
-#[update]
-async fn get_emissions() -> Result<Vec<Node>, String> {
-    let api_key = API_KEY.with(|k| k.borrow().clone());
-    let url = "https://dashboard-backend.fly.dev/nodes/getNodeEmissions";
-
-    let request = CanisterHttpRequestArgument {
-        url: url.to_string(),
-        method: HttpMethod::GET,
-        body: None,
-        max_response_bytes: None,
-        transform: Some(TransformContext {
-            function: TransformFunc(candid::Func {
-                principal: ic_cdk::api::id(),
-                method: "transform".to_string(),
-            }),
-            context: vec![],
-        }),
-        headers: vec![
-            HttpHeader {
-                name: "api-key".to_string(),
-                value: format!("{}", api_key),
-            },
-            HttpHeader {
-                name: "accept".to_string(),
-                value: "application/json".to_string(),
-            },
-        ],
+// Nodes of a fetched response with the emissions the model computes for them. Metadata of the
+// nodes goes to the registry and the nodes are counted as up since the previous sync.
+fn emissions_nodes(parsed: &mut ParsedEmissions, now: u64) -> Vec<Node> {
+    node_registry::merge_from_backend(
+        parsed
+            .records
+            .iter_mut()
+            .filter_map(|record| record.metadata.take())
+            .collect(),
+    );
+    emissions_model::record_uptime(parsed.records.iter().map(|record| record.name.as_str()), now);
+    parsed
+        .records
+        .drain(..)
+        .map(|record| Node {
+            computed_emissions: emissions_model::computed_emissions(&record.name).map(Co2e::from_kilos),
+            name: record.name,
+            total_emissions: Co2e::from_kilos(record.total_emissions),
+            offset_emissions: Co2e::ZERO,
+        })
+        .collect()
+}
+
+// Syncs the emissions right away and then every EMISSIONS_SYNC_INTERVAL, has to be called from
+// init and post_upgrade.
+fn start_emissions_sync() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(sync_emissions_cache()));
+    ic_cdk_timers::set_timer_interval(EMISSIONS_SYNC_INTERVAL, || {
+        ic_cdk::spawn(sync_emissions_cache())
+    });
+}
+
+// Sync in progress, the flag is cleared when it is dropped. That also happens when the callback
+// of an emissions fetch traps, so a trap does not stop the syncs for good.
+struct SyncRun;
+
+impl SyncRun {
+    fn start() -> Option<SyncRun> {
+        (!SYNC_IN_PROGRESS.with(|s| s.replace(true))).then_some(SyncRun)
+    }
+}
+
+impl Drop for SyncRun {
+    fn drop(&mut self) {
+        SYNC_IN_PROGRESS.with(|s| s.replace(false));
+    }
+}
+
+// refreshes the emissions cache, a failed fetch keeps the previous snapshot
+async fn sync_emissions_cache() {
+    let Some(_sync) = SyncRun::start() else {
+        return;
     };
 
-    match http_request(request, 21_000_000_000).await {
-        Ok((response,)) => {
-            let str_body = String::from_utf8(response.body)
-                .expect("Transformed response is not UTF-8 encoded.");
-            let json: serde_json::Value = serde_json::from_str(&str_body)
-                .map_err(|e| format!("Failed to parse JSON: {}", e))?;
-            let nodes: Vec<Node> = json
-                .as_array()
-                .unwrap()
-                .iter()
-                .map(|node| {
-                    let name = node["name"].as_str().unwrap().to_string();
-                    let total_emissions = node["total_emissions"].as_f64().unwrap();
-                    Node {
-                        name,
-                        total_emissions,
-                        offset_emissions: 0.0,
-                    }
-                })
-                .collect();
-            Ok(nodes)
+    let api_key = API_KEY.with(|k| k.borrow().clone());
+    let outcome = emissions_sources::fetch_emissions(&api_key).await;
+    let now = ic_cdk::api::time();
+    if let Some(divergence) = outcome.divergence.as_ref().filter(|d| d.exceeded) {
+        ic_cdk::println!(
+            "Emissions of {} nodes differ between {} and {} by more than {:.1}%",
+            divergence.diverging_count,
+            divergence.primary,
+            divergence.secondary,
+            divergence.threshold * 100.0
+        );
+    }
+    match outcome.emissions {
+        Some((source, mut parsed)) => {
+            let nodes = emissions_nodes(&mut parsed, now);
+            if parsed.rejected_count > 0 {
+                ic_cdk::println!("Emissions sync skipped {} rows", parsed.rejected_count);
+            }
+            record_history(&nodes, now);
+            EMISSIONS_CACHE.with(|c| {
+                *c.borrow_mut() = EmissionsCache {
+                    nodes,
+                    fetched_at: Some(now),
+                }
+            });
+            LAST_SYNC_ATTEMPT.with(|a| {
+                *a.borrow_mut() = SyncAttempt {
+                    at: Some(now),
+                    error: None,
+                    schema_version: parsed.schema_version,
+                    rejected_count: parsed.rejected_count,
+                    rejected_records: parsed.rejected,
+                    source: Some(source),
+                    source_errors: outcome.errors,
+                    divergence: outcome.divergence,
+                }
+            });
         }
-        Err((r, m)) => {
-            let message = format!(
-                "The http_request resulted into error. RejectionCode: {:?}, Error: {}",
-                r, m
-            );
-            Err(message)
+        None => {
+            let error = outcome.errors.last().map(|e| e.error.clone());
+            if let Some(e) = &error {
+                ic_cdk::println!("Emissions sync failed: {}", e);
+            }
+            LAST_SYNC_ATTEMPT.with(|a| {
+                let mut attempt = a.borrow_mut();
+                attempt.at = Some(now);
+                attempt.error = error;
+                attempt.source_errors = outcome.errors;
+                attempt.divergence = None;
+            });
         }
     }
 }
 
+// today's snapshot of every synced node, with the offsets applied to it so far
+fn record_history(nodes: &[Node], now: u64) {
+    let offsets: BTreeMap<String, Co2e> = NODES.with(|n| {
+        n.borrow()
+            .iter()
+            .map(|node| (node.name.clone(), node.offset_emissions))
+            .collect()
+    });
+    for node in nodes {
+        let offset = offsets.get(&node.name).copied().unwrap_or_default();
+        history::record(
+            &node.name,
+            node.total_emissions,
+            offset,
+            node.computed_emissions,
+            now,
+        );
+    }
+    history::trim(now);
+}
+
+// Nodes of the last sync with the offsets applied to them so far: what a node has left is what
+// it emitted minus its offsets in NODES. Nodes that were offset but are not in the sync any more
+// keep their values from NODES.
+fn current_nodes() -> Vec<Node> {
+    let mut offset: BTreeMap<String, Node> = NODES.with(|n| {
+        n.borrow()
+            .iter()
+            .map(|node| (node.name.clone(), node.clone()))
+            .collect()
+    });
+    let mut nodes: Vec<Node> = EMISSIONS_CACHE.with(|c| {
+        c.borrow()
+            .nodes
+            .iter()
+            .map(|node| {
+                let offset_emissions = offset
+                    .remove(&node.name)
+                    .map_or(Co2e::ZERO, |stored| stored.offset_emissions);
+                Node {
+                    total_emissions: node.total_emissions - offset_emissions,
+                    offset_emissions,
+                    ..node.clone()
+                }
+            })
+            .collect()
+    });
+    nodes.extend(offset.into_values());
+    nodes
+}
+
+// current_nodes, once the emissions were synced
+fn synced_nodes() -> Result<Vec<Node>, String> {
+    EMISSIONS_CACHE
+        .with(|c| c.borrow().fetched_at)
+        .ok_or_else(|| "Emissions were not synced yet".to_string())?;
+    Ok(current_nodes())
+}
+
+// nodes of the last successful sync
+fn cached_emissions() -> Result<Vec<Node>, String> {
+    EMISSIONS_CACHE.with(|c| {
+        let cache = c.borrow();
+        match cache.fetched_at {
+            Some(_) => Ok(cache.nodes.clone()),
+            None => Err("Emissions were not synced yet".to_string()),
+        }
+    })
+}
+
+// all nodes plus their emissions, as of the last sync
+#[query]
+fn get_emissions() -> Result<Vec<Node>, String> {
+    cached_emissions()
+}
+
+// emissions left and offset of every node, see current_nodes; aggregations and listings use these
+fn node_emissions() -> Vec<NodeEmissions> {
+    current_nodes()
+        .into_iter()
+        .map(|node| (node.name, node.total_emissions, node.offset_emissions))
+        .collect()
+}
+
+// A page of the nodes, filtered by subnet, provider or whether they are fully offset and sorted by
+// total, offset or net emissions. `start` is the position of the first node, at most 1000 nodes
+// are returned per call.
+#[query]
+fn list_nodes(
+    filter: NodeFilter,
+    sort: Option<NodeSort>,
+    start: Option<u64>,
+    limit: Option<u64>,
+) -> NodePage {
+    let limit = limit.unwrap_or(DEFAULT_NODE_PAGE).clamp(1, MAX_NODE_PAGE);
+    node_listing::page(
+        node_emissions().into_iter(),
+        &filter,
+        sort,
+        start.unwrap_or(0),
+        limit,
+    )
+}
+
+#[query]
+fn get_emissions_sync_status() -> EmissionsSyncStatus {
+    let now = ic_cdk::api::time();
+    let (last_synced_at, node_count) =
+        EMISSIONS_CACHE.with(|c| (c.borrow().fetched_at, c.borrow().nodes.len() as u64));
+    let attempt = LAST_SYNC_ATTEMPT.with(|a| a.borrow().clone());
+    EmissionsSyncStatus {
+        last_synced_at,
+        cache_age_seconds: last_synced_at
+            .map(|synced_at| now.saturating_sub(synced_at) / NANOS_PER_SECOND),
+        node_count,
+        last_attempt_at: attempt.at,
+        last_error: attempt.error,
+        schema_version: attempt.schema_version,
+        rejected_count: attempt.rejected_count,
+        rejected_records: attempt.rejected_records,
+        sync_interval_seconds: EMISSIONS_SYNC_INTERVAL.as_secs(),
+        source: attempt.source,
+        source_errors: attempt.source_errors,
+        divergence: attempt.divergence,
+    }
+}
+
+// sources the emissions are fetched from in priority order, their keys are not returned
+#[query]
+fn get_emissions_sources() -> Vec<EmissionsSourceView> {
+    emissions_sources::views()
+}
+
+// replaces the emissions sources, the first one is tried first and the others are fallbacks
+#[update]
+fn set_emissions_sources(sources: Vec<EmissionsSource>) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    emissions_sources::set_sources(sources)
+}
+
+// relative difference between two sources a sync reports as divergent, None turns the check off
+#[update]
+fn set_emissions_divergence_threshold(threshold: Option<f64>) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    emissions_sources::set_divergence_threshold(threshold)
+}
+
+// syncs the emissions cache without waiting for the timer
+#[update]
+async fn sync_emissions() -> Result<EmissionsSyncStatus, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    sync_emissions_cache().await;
+    Ok(get_emissions_sync_status())
+}
+
+// Adds an offset to the node in NODES, which keeps the offsets of a node summed over every call.
+// The given node is updated to the stored values.
+fn store_offset(node: &mut Node, offset: Co2e) {
+    node.total_emissions -= offset;
+    node.offset_emissions = NODES.with(|n| {
+        let mut nodes = n.borrow_mut();
+        match nodes.iter_mut().find(|n| n.name == node.name) {
+            Some(stored) => {
+                stored.total_emissions = node.total_emissions;
+                stored.offset_emissions += offset;
+                stored.offset_emissions
+            }
+            None => {
+                nodes.push(Node {
+                    offset_emissions: offset,
+                    ..node.clone()
+                });
+                offset
+            }
+        }
+    });
+}
+
+// splits the offset over the given nodes with the allocation strategy of the client, adds it to
+// the nodes in NODES and returns the amount that was offset from every node
+fn offset_nodes(mut nodes: Vec<&mut Node>, offset: Co2e, client: &str) -> Vec<NodeOffset> {
+    let emissions: Vec<(String, f64)> = nodes
+        .iter()
+        .map(|node| (node.name.clone(), node.total_emissions.kilos()))
+        .collect();
+    let caps: Vec<Co2e> = nodes.iter().map(|node| node.total_emissions).collect();
+    let shares = apportion(&allocation::allocate(client, &emissions, offset.kilos()), offset, &caps);
+
+    let mut applied = vec![];
+    for (node, offset_for_this_node) in nodes.iter_mut().zip(shares) {
+        if offset_for_this_node.is_zero() {
+            continue;
+        }
+        store_offset(node, offset_for_this_node);
+        applied.push(NodeOffset {
+            node: node.name.clone(),
+            offset: offset_for_this_node,
+        });
+    }
+    applied
+}
+
+// offset_nodes plus the certification of the nodes that were offset
+fn apply_offset(nodes: Vec<&mut Node>, offset: Co2e, client: &str) -> Vec<NodeOffset> {
+    let applied = offset_nodes(nodes, offset, client);
+    NODES.with(|n| {
+        for node in n.borrow().iter() {
+            if applied.iter().any(|allocation| allocation.node == node.name) {
+                certify_node(&node.name, node);
+            }
+        }
+    });
+    applied
+}
+
+// Picks the nodes an offset goes to and applies it, the nodes of the client are updated in place.
+// The selection is returned when the nodes were picked at random.
+async fn offset_client_nodes(
+    client: &mut Client,
+    offset: Co2e,
+    node_name: Option<String>,
+) -> Result<(Vec<NodeOffset>, Option<SelectionRecord>), String> {
+    if let Some(name) = node_name {
+        // The client specified a node_name.
+        // check if total emissions is 0
+        if client.nodes.iter().all(|n| n.total_emissions.is_zero()) {
+            return Err("No emissions offset because total emissions is 0".to_string());
+        }
+
+        // Found the node, offset the emissions.
+        let allocations = apply_offset(
+            client.nodes.iter_mut().filter(|n| n.name == name).take(1).collect(),
+            offset,
+            &client.client,
+        );
+        Ok((allocations, None))
+    } else if !client.nodes.is_empty() {
+        // The client is attached to some nodes, offset the emissions.
+        let allocations = apply_offset(client.nodes.iter_mut().collect(), offset, &client.client);
+        Ok((allocations, None))
+    } else {
+        // The client isn't attached to any nodes, select a random set of nodes and offset the emissions.
+        let mut selection = random_selection(None, None).await?;
+        let allocations = apply_offset(selection.nodes.iter_mut().collect(), offset, &client.client);
+        Ok((allocations, Some(selection.record())))
+    }
+}
+
 // offset emissions from nodes based on a client
 #[update]
 async fn offset_emissions(
     mut client: Client,
-    mut offset: f64,
+    offset: Co2e,
     node_name: Option<String>,
 ) -> String {
     // only authorized principals can call this function
-    let caller = caller();
-    let is_authorized = AUTHORIZED_PRINCIPALS.with(|p| {
-        let authorized_principals = p.borrow();
-        authorized_principals.is_empty() || authorized_principals.contains(&caller)
-    });
-
-    if !is_authorized {
+    if !is_authorized() {
         return serde_json::to_string(
             &json!({"error": "Unauthorized: the caller is not allowed to perform this action."}),
         )
@@ -209,148 +917,560 @@ async fn offset_emissions(
     }
 
     // If offset is 0, return early.
-    if offset == 0.0 {
+    if offset.is_zero() {
         return serde_json::to_string(
             &json!({"message": "No emissions offset because offset amount is 0"}),
         )
         .unwrap();
     }
 
-    if let Some(name) = node_name {
-        // The client specified a node_name.
-        // check if total emissions is 0
-        if client.nodes.iter().all(|n| n.total_emissions == 0.0) {
-            return serde_json::to_string(
-                &json!({"message": "No emissions offset because total emissions is 0"}),
-            )
-            .unwrap();
-        }
-
-        if let Some(node) = client.nodes.iter_mut().find(|n| n.name == name) {
-            // Found the node, offset the emissions.
-            let mut offset_for_this_node = offset.min(node.total_emissions);
-            node.total_emissions -= offset_for_this_node;
-            node.offset_emissions += offset_for_this_node;
-
-            // store Node in the NODES thread local
-            NODES.with(|n| {
-                let mut nodes = n.borrow_mut();
-                if let Some(n) = nodes.iter_mut().find(|n| n.name == name) {
-                    n.total_emissions = node.total_emissions;
-                    n.offset_emissions = node.offset_emissions;
-                } else {
-                    nodes.push(node.clone());
-                }
-            });
-        }
-    } else {
-        // The client didn't specify a node_name.
-        if !client.nodes.is_empty() {
-            // The client is attached to some nodes, offset the emissions.
-            for node in &mut client.nodes {
-                let offset_for_this_node = offset.min(node.total_emissions);
-                node.total_emissions -= offset_for_this_node;
-                node.offset_emissions += offset_for_this_node;
-
-                // store Node in the NODES thread local
-                NODES.with(|n| {
-                    let mut nodes = n.borrow_mut();
-                    if let Some(n) = nodes.iter_mut().find(|n| n.name == node.name) {
-                        n.total_emissions = node.total_emissions;
-                        n.offset_emissions = node.offset_emissions;
-                    } else {
-                        nodes.push(node.clone());
-                    }
-                });
-            }
-        } else {
-            // The client isn't attached to any nodes, select a random set of nodes and offset the emissions.
-            let nodes_future = select_random_nodes();
-            let nodes = nodes_future.await;
-            for mut node in nodes {
-                let offset_for_this_node = offset.min(node.total_emissions);
-                node.total_emissions -= offset_for_this_node;
-                node.offset_emissions += offset_for_this_node;
-
-                // store Node in the NODES thread local
-                NODES.with(|n| {
-                    let mut nodes = n.borrow_mut();
-                    if let Some(n) = nodes.iter_mut().find(|n| n.name == node.name) {
-                        n.total_emissions = node.total_emissions;
-                        n.offset_emissions = node.offset_emissions;
-                    } else {
-                        nodes.push(node.clone());
-                    }
-                });
-            }
-        }
+    if let Err(message) = offset_client_nodes(&mut client, offset, node_name).await {
+        return serde_json::to_string(&json!({ "message": message })).unwrap();
     }
 
     // Serialize the updated nodes into a JSON string
     serde_json::to_string(&client.nodes).unwrap()
 }
 
+// offsets the given nodes with the default allocation strategy, the nodes are stored in NODES
 #[update]
-fn offset_from_nodes(mut nodes: Vec<Node>, mut offset: f64) {
-    // Sort the nodes from highest to lowest total_emissions.
-    nodes.sort_by(|a, b| b.total_emissions.partial_cmp(&a.total_emissions).unwrap());
+fn offset_from_nodes(mut nodes: Vec<Node>, offset: Co2e) -> Result<Vec<NodeOffset>, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    if offset.is_zero() {
+        return Err("Offset must be positive".to_string());
+    }
 
-    for mut node in nodes {
-        if offset <= 0.0 {
-            break;
-        }
+    Ok(apply_offset(nodes.iter_mut().collect(), offset, ""))
+}
 
-        let offset_for_this_node = offset.min(node.total_emissions);
-        node.total_emissions -= offset_for_this_node;
-        node.offset_emissions += offset_for_this_node;
-        offset -= offset_for_this_node;
-    }
+// Samples nodes with emissions, weighted by their emissions. Without a seed a fresh one is taken
+// from raw_rand, passing the seed of an earlier selection reproduces it.
+async fn random_selection(
+    sample_size: Option<u64>,
+    seed: Option<Vec<u8>>,
+) -> Result<RandomSelection, String> {
+    let sample_size = sample_size.unwrap_or_else(|| RANDOM_SAMPLE_SIZE.with(|s| *s.borrow()));
+    let seed = match seed {
+        Some(seed) => seed,
+        None => raw_rand()
+            .await
+            .map(|(seed,)| seed)
+            .map_err(|(code, message)| format!("raw_rand failed: {:?} {}", code, message))?,
+    };
+    let candidates = synced_nodes()?;
+
+    let weights: Vec<(String, f64)> = candidates
+        .iter()
+        .map(|node| (node.name.clone(), node.total_emissions.kilos()))
+        .collect();
+    let nodes = weighted_sample(&seed, &weights, sample_size as usize)
+        .into_iter()
+        .map(|i| candidates[i].clone())
+        .collect();
+
+    Ok(RandomSelection {
+        seed,
+        sample_size,
+        candidates_hash: candidates_hash(&weights),
+        nodes,
+    })
 }
 
 #[update]
-async fn select_random_nodes() -> Vec<Node> {
-    let emissions_result = get_emissions().await;
+async fn select_random_nodes(
+    sample_size: Option<u64>,
+    seed: Option<Vec<u8>>,
+) -> Result<RandomSelection, String> {
+    random_selection(sample_size, seed).await
+}
 
-    let mut nodes: Vec<Node> = match emissions_result {
-        Ok(emissions) => emissions
+// number of nodes an offset is spread over when the client has no nodes
+#[update]
+fn set_random_sample_size(sample_size: u64) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    if sample_size == 0 {
+        return Err("Sample size must be at least 1".to_string());
+    }
+    RANDOM_SAMPLE_SIZE.with(|s| *s.borrow_mut() = sample_size);
+    Ok(())
+}
+
+fn record_ticket_price(price: f64) {
+    if price.is_finite() && price > 0.0 {
+        TICKET_PRICE.with(|p| *p.borrow_mut() = Some(price));
+    }
+}
+
+// CO2e a payment offsets. A rate set for its project, then vendor, converts the tickets, else
+// the payment counts for what the wallet bought with it.
+fn payment_co2e(payment: &Payment) -> Co2e {
+    let kilos_per_ticket = configured_rate(payment.project.as_deref(), payment.vendor.as_deref())
+        .map(|(_, kilos_per_ticket)| kilos_per_ticket);
+    match (kilos_per_ticket, payment.co2e) {
+        (None, Some(co2e)) => co2e,
+        (kilos_per_ticket, _) => Co2e::from_kilos(
+            payment.ticket_count * kilos_per_ticket.unwrap_or(DEFAULT_KILOS_PER_TICKET),
+        ),
+    }
+}
+
+// rate set for a project, else for its vendor, with the project or vendor it was set for
+fn configured_rate(project: Option<&str>, vendor: Option<&str>) -> Option<(String, f64)> {
+    OFFSET_RATES.with(|r| {
+        let rates = r.borrow();
+        [project, vendor]
             .into_iter()
-            .filter(|node| node.total_emissions > 0.0)
-            .collect(),
-        Err(_) => vec![], // Handle the error appropriately.
+            .flatten()
+            .find_map(|key| Some((key.to_string(), *rates.get(key)?)))
+    })
+}
+
+// Kilos per ticket that balances and forecasts convert at, with the project or vendor the rate
+// was set for. Without a project it is the one of the latest offset in the ledger, like the
+// ticket price is the one of the latest payment. Projects without a rate use
+// DEFAULT_KILOS_PER_TICKET.
+fn ticket_rate(project: Option<String>) -> (f64, Option<String>) {
+    let Some(project) = project.or_else(offset_ledger::latest_project) else {
+        return (DEFAULT_KILOS_PER_TICKET, None);
     };
+    let vendor = projects::get(&project).and_then(|project| project.vendor);
+    match configured_rate(Some(&project), vendor.as_deref()) {
+        Some((source, kilos_per_ticket)) => (kilos_per_ticket, Some(source)),
+        None => (DEFAULT_KILOS_PER_TICKET, None),
+    }
+}
 
-    // Sort the nodes from highest to lowest total_emissions.
-    nodes.sort_by(|a, b| b.total_emissions.partial_cmp(&a.total_emissions).unwrap());
+// client with those of the nodes it runs on
+// A named client without any synced node would have its payments offset on randomly sampled
+// nodes, an attribution that cannot be corrected later. Its payments wait until it has nodes.
+fn has_attributable_nodes(client: &Client) -> bool {
+    client.client.is_empty() || !client.nodes.is_empty()
+}
 
-    nodes
+fn client_with_nodes(name: String, node_ids: &[String], all_nodes: &[Node]) -> Client {
+    Client {
+        client: name,
+        nodes: all_nodes
+            .iter()
+            .filter(|node| node_ids.contains(&node.name))
+            .cloned()
+            .collect(),
+    }
 }
 
+// Applies every payment of a wallet that is not in the offset ledger yet to the nodes of the
+// client. `recorded` holds every payment the ledger now knows about, applied now or before.
+async fn apply_wallet_payments(
+    wallet: Principal,
+    client: &mut Client,
+    payments: Vec<Payment>,
+    node_name: Option<String>,
+) -> (Vec<AppliedPayment>, Vec<SkippedPayment>, Vec<PaymentKey>) {
+    let mut applied = vec![];
+    let mut skipped = vec![];
+    let mut recorded = vec![];
+    for payment in payments {
+        let key = payment.key();
+        record_ticket_price(payment.ticket_price);
+
+        // claim the payment before awaiting so a concurrent call cannot apply it as well
+        let claim = match offset_ledger::claim(wallet, &key) {
+            Ok(claim) => claim,
+            Err(reason) => {
+                if offset_ledger::is_recorded(wallet, &key) {
+                    recorded.push(key.clone());
+                }
+                skipped.push(SkippedPayment {
+                    block_height: key.block_height,
+                    reason,
+                });
+                continue;
+            }
+        };
+
+        // payments recorded before wallets sent the project, or naming a project that is not
+        // registered, do not fund one
+        let project = match payment.project.as_deref() {
+            Some(reference) => match projects::funded(reference, payment.vendor.as_deref()) {
+                Ok(project) => project,
+                Err(reason) => {
+                    skipped.push(SkippedPayment {
+                        block_height: key.block_height,
+                        reason,
+                    });
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        let co2e = payment_co2e(&payment);
+        let node_name = payment.node_id.clone().or_else(|| node_name.clone());
+        let result = if !co2e.is_zero() {
+            offset_client_nodes(client, co2e, node_name).await
+        } else {
+            Err("No emissions offset because offset amount is 0".to_string())
+        };
+
+        match result {
+            Ok((allocations, selection)) if !allocations.is_empty() => {
+                let allocated: Co2e = allocations.iter().map(|allocation| allocation.offset).sum();
+                let entry =
+                    offset_ledger::record_offset(
+                        claim,
+                        co2e,
+                        allocations,
+                        selection.clone(),
+                        project,
+                        Some(payment.cawa_url.clone()).filter(|url| !url.is_empty()),
+                    );
+                recorded.push(key.clone());
+                applied.push(AppliedPayment {
+                    block_height: key.block_height,
+                    payer: payment.payer.clone(),
+                    co2e,
+                    allocations: entry.allocations,
+                    unallocated: co2e - allocated,
+                    ledger_entry: entry.id,
+                    random_seed: selection.as_ref().map(|selection| to_hex(&selection.seed)),
+                    random_sample_size: selection.as_ref().map(|selection| selection.sample_size),
+                    candidates_hash: selection.map(|selection| to_hex(&selection.candidates_hash)),
+                });
+            }
+            outcome => {
+                skipped.push(SkippedPayment {
+                    block_height: key.block_height,
+                    reason: outcome
+                        .err()
+                        .unwrap_or_else(|| "No node to offset".to_string()),
+                });
+            }
+        }
+    }
+    (applied, skipped, recorded)
+}
+
+// Applies every payment of the calling wallet that is not in the offset ledger yet to the nodes
+// of the client. The response lists, per payment, the kilos of CO2e and how they were split over
+// the nodes, `recorded` holds every payment of the call the ledger now knows about. Payments are
+// recorded under the wallet that sent them, so only registered wallet canisters can call this.
 #[update]
 async fn get_offset_emissions(
     simple_client: SimpleClient,
     payment: Vec<Payment>,
     node_name: Option<String>,
 ) -> String {
-    let all_nodes_result = get_emissions().await;
-
-    match all_nodes_result {
-        Ok(all_nodes) => {
-            let node_ids = simple_client.node_ids.clone();
-            let nodes: Vec<Node> = all_nodes
-                .into_iter()
-                .filter(|node| node_ids.contains(&node.name))
-                .collect();
-            let client = Client {
-                client: simple_client.name,
-                nodes,
-            };
-            let offset = payment.iter().fold(0.0, |acc, p| acc);
-            offset_emissions(client, offset, node_name).await
+    let wallet = caller();
+    if !is_wallet_canister(&wallet) {
+        return serde_json::to_string(
+            &json!({"error": "Unauthorized: the caller is not a registered wallet canister."}),
+        )
+        .unwrap();
+    }
+
+    let all_nodes = match synced_nodes() {
+        Ok(all_nodes) => all_nodes,
+        Err(e) => return format!("Error getting emissions: {}", e),
+    };
+
+    clients::add_nodes(&simple_client.name, &simple_client.node_ids);
+    let mut client = client_with_nodes(simple_client.name, &simple_client.node_ids, &all_nodes);
+    let (applied, skipped, recorded) =
+        apply_wallet_payments(wallet, &mut client, payment, node_name).await;
+
+    serde_json::to_string(&json!({
+        "applied": applied,
+        "skipped": skipped,
+        "recorded": recorded,
+        "nodes": client.nodes,
+    }))
+    .unwrap()
+}
+
+// Settled payments pushed by a registered wallet canister. Payments are applied to the nodes of
+// their client in the client registry (plus the node a payment names), the ones the ledger knows
+// about are returned so the wallet can stop resending them.
+#[update]
+async fn notify_settled_payments(payments: Vec<Payment>) -> Result<Vec<PaymentKey>, String> {
+    let wallet = caller();
+    if !is_wallet_canister(&wallet) {
+        return Err("Unauthorized: the caller is not a registered wallet canister.".to_string());
+    }
+    let all_nodes = synced_nodes()?;
+
+    let mut by_client: BTreeMap<String, Vec<Payment>> = BTreeMap::new();
+    for payment in payments {
+        by_client
+            .entry(payment.client.clone().unwrap_or_default())
+            .or_default()
+            .push(payment);
+    }
+
+    let mut recorded = vec![];
+    for (name, payments) in by_client {
+        let payment_nodes: Vec<String> = payments
+            .iter()
+            .filter_map(|payment| payment.node_id.clone())
+            .collect();
+        if !name.is_empty() && !payment_nodes.is_empty() {
+            clients::add_nodes(&name, &payment_nodes);
+        }
+        let mut node_ids = clients::get(&name).map(|client| client.node_ids).unwrap_or_default();
+        node_ids.extend(payment_nodes);
+        let mut client = client_with_nodes(name, &node_ids, &all_nodes);
+        // left out of `recorded`, so the wallet sends them again
+        if !has_attributable_nodes(&client) {
+            ic_cdk::println!(
+                "{} payments of {} held back: client {} has no known nodes",
+                payments.len(),
+                wallet,
+                client.client
+            );
+            continue;
         }
-        Err(e) => {
-            format!("Error getting emissions: {}", e)
+        let (_, skipped, payments_recorded) =
+            apply_wallet_payments(wallet, &mut client, payments, None).await;
+        for payment in skipped {
+            ic_cdk::println!("Payment {} of {} not applied: {}", payment.block_height, wallet, payment.reason);
         }
+        recorded.extend(payments_recorded);
+    }
+    Ok(recorded)
+}
+
+fn is_wallet_canister(principal: &Principal) -> bool {
+    WALLET_CANISTERS.with(|w| w.borrow().contains(principal))
+}
+
+// wallet canisters that may send their payments with get_offset_emissions and
+// notify_settled_payments
+#[update]
+fn register_wallet_canister(wallet: Principal) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    WALLET_CANISTERS.with(|w| w.borrow_mut().insert(wallet));
+    Ok(())
+}
+
+#[update]
+fn unregister_wallet_canister(wallet: Principal) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    WALLET_CANISTERS.with(|w| w.borrow_mut().remove(&wallet));
+    Ok(())
+}
+
+#[query]
+fn get_wallet_canisters() -> Vec<Principal> {
+    WALLET_CANISTERS.with(|w| w.borrow().iter().cloned().collect())
+}
+
+// Reverses the offset a wallet recorded for a payment, e.g. after the payment was refunded. The
+// allocations are taken back from the nodes and the payment can not be applied again.
+#[update]
+fn reverse_offset(
+    wallet: Principal,
+    payment: PaymentKey,
+    reason: String,
+) -> Result<LedgerEntry, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+
+    let reversal = offset_ledger::record_reversal(wallet, payment, reason)?;
+    NODES.with(|n| {
+        let mut nodes = n.borrow_mut();
+        for allocation in &reversal.allocations {
+            if let Some(node) = nodes.iter_mut().find(|node| node.name == allocation.node) {
+                node.offset_emissions -= allocation.offset;
+                node.total_emissions += allocation.offset;
+                certify_node(&node.name, node);
+            }
+        }
+    });
+    Ok(reversal)
+}
+
+// Imports node metadata, e.g. from the public dashboard. Imported values take precedence over
+// the ones the emissions backend reports. Returns the number of imported nodes.
+#[update]
+fn import_node_metadata(nodes: Vec<NodeMetadata>) -> Result<u64, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    node_registry::import(nodes)
+}
+
+#[update]
+fn remove_node_metadata(node_id: String) -> Result<NodeMetadata, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    node_registry::remove(&node_id).ok_or_else(|| format!("Node {} not found", node_id))
+}
+
+#[query]
+fn get_node_metadata(node_id: String) -> Option<NodeMetadata> {
+    node_registry::get(&node_id)
+}
+
+#[query]
+fn list_node_metadata() -> Vec<NodeMetadata> {
+    node_registry::registry_snapshot()
+}
+
+// Emissions of a node computed from its inputs next to the fetched ones over the same days,
+// with every input of the computation.
+#[query]
+fn get_modelled_emissions(node_id: String) -> ModelledEmissions {
+    emissions_model::compute(&node_id)
+}
+
+// modelled emissions of every node of the last sync
+#[query]
+fn list_modelled_emissions() -> Vec<ModelledEmissions> {
+    EMISSIONS_CACHE.with(|c| {
+        c.borrow()
+            .nodes
+            .iter()
+            .map(|node| emissions_model::compute(&node.name))
+            .collect()
+    })
+}
+
+#[query]
+fn get_emissions_model() -> ModelSettings {
+    emissions_model::model_snapshot()
+}
+
+// average power draw of a hardware generation in watts, None removes it
+#[update]
+fn set_hardware_profile(hardware_generation: String, power_watts: Option<f64>) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    emissions_model::set_hardware_profile(&hardware_generation, power_watts)
+}
+
+// gCO2e per kWh of a grid region or country, None removes it
+#[update]
+fn set_grid_intensity(region: String, grams_per_kwh: Option<f64>) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    emissions_model::set_grid_intensity(&region, grams_per_kwh)
+}
+
+// PUE of a data center, None makes it use the default PUE
+#[update]
+fn set_data_center_pue(data_center: String, pue: Option<f64>) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    emissions_model::set_data_center_pue(&data_center, pue)
+}
+
+#[update]
+fn set_default_pue(pue: f64) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    emissions_model::set_default_pue(pue)
+}
+
+// uptime of a node in seconds, the syncs add to it from then on
+#[update]
+fn set_node_uptime(node_id: String, uptime_seconds: u64) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    emissions_model::set_uptime(&node_id, uptime_seconds);
+    Ok(())
+}
+
+// entries of the offset ledger after the given entry id, oldest first
+#[query]
+fn get_offset_ledger(start: Option<u64>, limit: Option<u64>) -> Vec<LedgerEntry> {
+    let limit = limit.unwrap_or(MAX_LEDGER_PAGE).min(MAX_LEDGER_PAGE);
+    offset_ledger::entries(start, limit as usize)
+}
+
+// offset and reversal entries a wallet recorded for one payment
+#[query]
+fn get_payment_ledger_entries(wallet: Principal, payment: PaymentKey) -> Vec<LedgerEntry> {
+    offset_ledger::payment_entries(wallet, &payment)
+}
+
+// Sets how offsets are split over nodes, for one client or as the default for every client
+// without a strategy of its own.
+#[update]
+fn set_allocation_strategy(
+    client: Option<String>,
+    strategy: AllocationStrategy,
+) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    allocation::set_strategy(client, strategy);
+    Ok(())
+}
+
+// the client goes back to the default strategy
+#[update]
+fn clear_allocation_strategy(client: String) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    allocation::clear_strategy(&client);
+    Ok(())
+}
+
+// node preferences of a client for the Weighted strategy, nodes without a weight get nothing
+#[update]
+fn set_client_node_weights(client: String, weights: Vec<(String, f64)>) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    allocation::set_weights(client, weights)
+}
+
+#[query]
+fn get_allocation_settings() -> AllocationSettings {
+    allocation::settings_snapshot()
+}
+
+// sets how many kilos of CO2e one ticket of a project or vendor offsets
+#[update]
+fn set_kilos_per_ticket(project_or_vendor: String, kilos: f64) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    if !kilos.is_finite() || kilos <= 0.0 {
+        return Err("Kilos per ticket must be positive".to_string());
+    }
+    OFFSET_RATES.with(|r| r.borrow_mut().insert(project_or_vendor, kilos));
+    Ok(())
+}
+
+#[update]
+fn remove_kilos_per_ticket(project_or_vendor: String) -> Result<(), String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
     }
+    OFFSET_RATES.with(|r| r.borrow_mut().remove(&project_or_vendor));
+    Ok(())
+}
+
+// configured rates, payments of any other project or vendor use DEFAULT_KILOS_PER_TICKET
+#[query]
+fn get_kilos_per_ticket() -> Vec<(String, f64)> {
+    OFFSET_RATES.with(|r| r.borrow().iter().map(|(k, v)| (k.clone(), *v)).collect())
 }
 
 // get offset emissions for a node
@@ -368,61 +1488,347 @@ fn get_node_offset_emissions(node_name: String) -> String {
     })
 }
 
-// get offset emissions for a client
+// Current state of the nodes of a client, as list_nodes reports them: the emissions of the last
+// sync net of the offsets applied so far. Nodes that are unknown are left out.
+fn client_nodes(client: &ClientRecord) -> Vec<Node> {
+    let nodes = current_nodes();
+    client
+        .node_ids
+        .iter()
+        .filter_map(|node_id| nodes.iter().find(|node| &node.name == node_id).cloned())
+        .collect()
+}
+
+// get offset emissions for a client, per node and in total
 #[query]
-fn get_client_offset_emissions(client_name: String) -> String {
-    NODES.with(|n| {
-        let nodes = n.borrow();
-        let client_nodes: Vec<Node> = nodes
+fn get_client_offset_emissions(client_name: String) -> Result<ClientOffsetEmissions, String> {
+    let client = clients::get(&client_name).ok_or_else(|| format!("Client {} not found", client_name))?;
+    let nodes = client_nodes(&client);
+    let net_emissions: Co2e = nodes.iter().map(|node| node.total_emissions).sum();
+    let offset_emissions: Co2e = nodes.iter().map(|node| node.offset_emissions).sum();
+    Ok(ClientOffsetEmissions {
+        client: client.name,
+        nodes,
+        total_emissions: net_emissions + offset_emissions,
+        offset_emissions,
+        net_emissions,
+    })
+}
+
+#[update]
+fn register_client(name: String, node_ids: Vec<String>) -> Result<ClientRecord, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    clients::register(name, node_ids)
+}
+
+// replaces the nodes of a client
+#[update]
+fn set_client_nodes(name: String, node_ids: Vec<String>) -> Result<ClientRecord, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    clients::set_nodes(&name, node_ids)
+}
+
+#[update]
+fn remove_client(name: String) -> Result<ClientRecord, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    clients::remove(&name).ok_or_else(|| format!("Client {} not found", name))
+}
+
+#[query]
+fn get_clients() -> Vec<ClientRecord> {
+    clients::clients_snapshot()
+}
+
+// clients a node is attached to
+#[query]
+fn get_node_clients(node_id: String) -> Vec<String> {
+    clients::clients_of(&node_id)
+}
+
+// certified variant of get_node_offset_emissions, an empty list means the node is unknown
+#[query]
+fn get_node_offset_emissions_certified(node_name: String) -> CertifiedNodes {
+    let nodes = NODES.with(|n| {
+        n.borrow()
             .iter()
-            .filter(|n| n.name.starts_with(&client_name))
+            .filter(|n| n.name == node_name)
             .cloned()
-            .collect();
-        serde_json::to_string(&client_nodes).unwrap()
-    })
+            .collect()
+    });
+    let (certificate, witness) = node_witness(&node_name);
+    CertifiedNodes {
+        nodes,
+        certificate,
+        witness,
+    }
+}
+
+// certified variant of get_client_offset_emissions
+#[query]
+fn get_client_offset_emissions_certified(client_name: String) -> CertifiedNodes {
+    let node_ids = clients::get(&client_name)
+        .map(|client| client.node_ids)
+        .unwrap_or_default();
+    let nodes = NODES.with(|n| {
+        n.borrow()
+            .iter()
+            .filter(|n| node_ids.contains(&n.name))
+            .cloned()
+            .collect()
+    });
+    let (certificate, witness) = nodes_witness();
+    CertifiedNodes {
+        nodes,
+        certificate,
+        witness,
+    }
+}
+
+// emissions of the nodes of a subnet, nodes are assigned to subnets by the node registry
+#[query]
+fn get_subnet_emissions(subnet_id: String) -> EmissionsSummary {
+    aggregation::summarize_group(node_emissions().into_iter(), EmissionsGrouping::Subnet, &subnet_id)
+}
+
+#[query]
+fn get_provider_emissions(provider: String) -> EmissionsSummary {
+    aggregation::summarize_group(node_emissions().into_iter(), EmissionsGrouping::Provider, &provider)
+}
+
+#[query]
+fn get_datacenter_emissions(data_center: String) -> EmissionsSummary {
+    aggregation::summarize_group(
+        node_emissions().into_iter(),
+        EmissionsGrouping::DataCenter,
+        &data_center,
+    )
+}
+
+// groups with the highest net emissions first
+#[query]
+fn get_emissions_ranking(grouping: EmissionsGrouping, limit: u64) -> Vec<EmissionsSummary> {
+    let limit = limit.min(MAX_RANKING_SIZE) as usize;
+    aggregation::ranking(node_emissions().into_iter(), grouping, limit)
+}
+
+// emitted, offset and net emissions of a node per day, week or month, `from` and `to` are
+// timestamps in nanoseconds
+#[query]
+fn get_node_history(
+    node_id: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    granularity: Granularity,
+) -> Vec<HistoryPoint> {
+    history::points(&[node_id], from, to, granularity)
+}
+
+// Carbon balance of a client between two timestamps, all time without them: what its nodes
+// emitted, the offsets the ledger recorded for it, and the tickets still needed for net zero at
+// the rate of `project`, see ticket_rate.
+#[query]
+fn get_client_carbon_balance(
+    client_name: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    project: Option<String>,
+) -> Result<CarbonBalance, String> {
+    let client = clients::get(&client_name).ok_or_else(|| format!("Client {} not found", client_name))?;
+    let emissions = history::emitted_between(&client.node_ids, from, to);
+    let entries = offset_ledger::entries_between(from, to);
+    Ok(balance::balance(
+        client.name.clone(),
+        from,
+        to,
+        emissions,
+        balance::client_offsets(&client.name, &client.node_ids, &entries),
+        ticket_rate(project),
+        TICKET_PRICE.with(|p| *p.borrow()),
+    ))
+}
+
+// Expected emissions of a client, or of the nodes the registry places in a subnet, for the
+// month, quarter or year after the latest snapshot, with the ticket budget that offsets them at
+// the current ticket price and the rate of `project`, see ticket_rate.
+#[query]
+fn get_emissions_forecast(
+    target: ForecastTarget,
+    horizon: ForecastHorizon,
+    method: ForecastMethod,
+    project: Option<String>,
+) -> Result<EmissionsForecast, String> {
+    let node_ids: Vec<String> = match &target {
+        ForecastTarget::Client(client_name) => {
+            clients::get(client_name)
+                .ok_or_else(|| format!("Client {} not found", client_name))?
+                .node_ids
+        }
+        ForecastTarget::Subnet(subnet_id) => node_registry::registry_snapshot()
+            .into_iter()
+            .filter(|node| node.subnet_id.as_ref() == Some(subnet_id))
+            .map(|node| node.node_id)
+            .collect(),
+    };
+    forecast::forecast(
+        target,
+        &node_ids,
+        horizon,
+        method,
+        ticket_rate(project),
+        TICKET_PRICE.with(|p| *p.borrow()),
+    )
+}
+
+// where the figures of a report come from
+fn report_data_sources() -> Vec<String> {
+    emissions_sources::views()
+        .into_iter()
+        .map(|source| format!("Emissions source {}: {}", source.name, source.url))
+        .chain([
+            "Node registry of node_manager (subnet, data center, country)".to_string(),
+            "Offset ledger of node_manager, with the vendor proof of every payment".to_string(),
+        ])
+        .collect()
+}
+
+fn client_esg_report(client_name: &str, from: Option<u64>, to: Option<u64>) -> Result<EsgReport, String> {
+    let client = clients::get(client_name).ok_or_else(|| format!("Client {} not found", client_name))?;
+    Ok(report::build(
+        &client,
+        from,
+        to,
+        &offset_ledger::entries_between(from, to),
+        report_data_sources(),
+        ic_cdk::api::time(),
+    ))
+}
+
+// GHG Protocol report of a client between two timestamps, all time without them
+#[query]
+fn get_client_esg_report(
+    client_name: String,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Result<EsgReport, String> {
+    client_esg_report(&client_name, from, to)
+}
+
+// the report of get_client_esg_report as a JSON or CSV document
+#[query]
+fn export_client_esg_report(
+    client_name: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    format: ReportFormat,
+) -> Result<String, String> {
+    let report = client_esg_report(&client_name, from, to)?;
+    match format {
+        ReportFormat::Json => serde_json::to_string_pretty(&report).map_err(|e| e.to_string()),
+        ReportFormat::Csv => Ok(report::to_csv(&report)),
+    }
+}
+
+// history summed over the nodes of a client
+#[query]
+fn get_client_history(
+    client_name: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    granularity: Granularity,
+) -> Result<Vec<HistoryPoint>, String> {
+    let client = clients::get(&client_name).ok_or_else(|| format!("Client {} not found", client_name))?;
+    Ok(history::points(&client.node_ids, from, to, granularity))
 }
 
 #[query]
 fn get_projects() -> Vec<Project> {
-    PROJECTS.with(|p| p.borrow().clone())
+    projects::projects_snapshot()
+}
+
+#[query]
+fn get_project(project_id: String) -> Option<Project> {
+    projects::get(&project_id)
 }
 
-#[update]
 // method that adds projects to the project list
-fn add_project(project: Project) {
-    PROJECTS.with(|p| {
-        let mut projects = p.borrow_mut();
-        projects.push(project);
-    });
+#[update]
+fn add_project(project: ProjectInput) -> Result<Project, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    projects::create(project)
 }
 
-// retrieve icons for specific projects
-// #[query]
-// fn get_project_icon(project_id: String) -> Option<String> {
-//     PROJECTS.with(|p| {
-//         let projects = p.borrow();
-//         projects
-//             .iter()
-//             .find(|project| project.id.contains(&project_id))
-//             .and_then(|project| project.icon.clone())
-//     })
-// }
+#[update]
+fn update_project(project: ProjectInput) -> Result<Project, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    projects::update(project)
+}
 
+// retired projects stay listed for the payments that funded them
 #[update]
-pub fn remove_project(project_id: String) {
-    PROJECTS.with(|p| {
-        let mut projects = p.borrow_mut();
-        projects.retain(|project| !project.id.contains(&project_id));
-    });
+fn retire_project(project_id: String) -> Result<Project, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    projects::set_status(&project_id, ProjectStatus::Retired)
 }
 
-// delete all projects
 #[update]
-fn delete_all_projects() {
-    PROJECTS.with(|p| {
-        let mut projects = p.borrow_mut();
-        projects.clear();
-    });
+fn reactivate_project(project_id: String) -> Result<Project, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    projects::set_status(&project_id, ProjectStatus::Active)
+}
+
+// payments that funded a project, reversed payments are not counted
+#[query]
+fn get_project_funding(project_id: String) -> ProjectFunding {
+    let entries = offset_ledger::project_entries(&project_id);
+    let count = |kind: LedgerEntryKind| entries.iter().filter(|entry| entry.kind == kind).count();
+    let payment_count =
+        count(LedgerEntryKind::Offset).saturating_sub(count(LedgerEntryKind::Reversal)) as u64;
+    let sum = |kind: LedgerEntryKind| -> Co2e {
+        entries.iter().filter(|entry| entry.kind == kind).map(|entry| entry.co2e).sum()
+    };
+    ProjectFunding {
+        project_id,
+        payment_count,
+        co2e: sum(LedgerEntryKind::Offset) - sum(LedgerEntryKind::Reversal),
+        ledger_entries: entries.iter().map(|entry| entry.id).collect(),
+    }
+}
+
+// projects that funded payments can only be retired
+#[update]
+pub fn remove_project(project_id: String) -> Result<Project, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    if !offset_ledger::project_entries(&project_id).is_empty() {
+        return Err(format!("Project {} has payments, retire it instead", project_id));
+    }
+    projects::remove(&project_id).ok_or_else(|| format!("Project {} not found", project_id))
+}
+
+// delete all projects that have no payments, returns how many were deleted
+#[update]
+fn delete_all_projects() -> Result<u64, String> {
+    if !is_authorized() {
+        return Err("Unauthorized: the caller is not allowed to perform this action.".to_string());
+    }
+    Ok(projects::retain(|project| {
+        !offset_ledger::project_entries(&project.id).is_empty()
+    }))
 }
 
 #[update(name = "registerPayment")]
@@ -476,3 +1882,82 @@ async fn register_payment(amount: u64) -> String {
 }
 
 export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn synced(nodes: Vec<(&str, f64)>) {
+        let nodes = nodes
+            .into_iter()
+            .map(|(name, kilos)| Node {
+                name: name.to_string(),
+                total_emissions: Co2e::from_kilos(kilos),
+                offset_emissions: Co2e::ZERO,
+                computed_emissions: None,
+            })
+            .collect();
+        EMISSIONS_CACHE.with(|c| {
+            *c.borrow_mut() = EmissionsCache {
+                nodes,
+                fetched_at: Some(0),
+            }
+        });
+    }
+
+    #[test]
+    fn offsets_of_payments_to_one_node_add_up() {
+        synced(vec![("node-a", 10.0)]);
+        for _ in 0..2 {
+            let node_ids = ["node-a".to_string()];
+            let mut client = client_with_nodes("client".to_string(), &node_ids, &synced_nodes().unwrap());
+            let offset = Co2e::from_kilos(3.0);
+            let allocations = offset_nodes(client.nodes.iter_mut().collect(), offset, "client");
+            assert_eq!(allocations.len(), 1);
+        }
+
+        let node = current_nodes().into_iter().find(|node| node.name == "node-a").unwrap();
+        assert_eq!(node.offset_emissions, Co2e::from_kilos(6.0));
+        assert_eq!(node.total_emissions, Co2e::from_kilos(4.0));
+        let stored = NODES.with(|n| n.borrow()[0].offset_emissions);
+        assert_eq!(stored, Co2e::from_kilos(6.0));
+    }
+
+    #[test]
+    fn payments_of_clients_without_nodes_are_held_back() {
+        synced(vec![("node-a", 10.0)]);
+        let all_nodes = synced_nodes().unwrap();
+        let unknown = client_with_nodes("openchat".to_string(), &[], &all_nodes);
+        assert!(!has_attributable_nodes(&unknown));
+        let unsynced = client_with_nodes("openchat".to_string(), &["node-x".to_string()], &all_nodes);
+        assert!(!has_attributable_nodes(&unsynced));
+        let known = client_with_nodes("openchat".to_string(), &["node-a".to_string()], &all_nodes);
+        assert!(has_attributable_nodes(&known));
+        // payments without a client keep being sampled over all nodes
+        let anonymous = client_with_nodes(String::new(), &[], &all_nodes);
+        assert!(has_attributable_nodes(&anonymous));
+    }
+
+    #[test]
+    fn client_breakdown_follows_the_latest_sync() {
+        synced(vec![("node-a", 10.0), ("node-b", 5.0), ("node-c", 1.0)]);
+        let node_ids = ["node-a".to_string()];
+        let mut client = client_with_nodes("client".to_string(), &node_ids, &synced_nodes().unwrap());
+        offset_nodes(client.nodes.iter_mut().collect(), Co2e::from_kilos(3.0), "client");
+        // emissions keep growing after the offset
+        synced(vec![("node-a", 15.0), ("node-b", 5.0), ("node-c", 1.0)]);
+
+        let record = ClientRecord {
+            name: "client".to_string(),
+            node_ids: vec!["node-b".to_string(), "node-a".to_string(), "node-x".to_string()],
+            created_at: 0,
+            updated_at: 0,
+        };
+        let nodes = client_nodes(&record);
+        let names: Vec<&str> = nodes.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, ["node-b", "node-a"]);
+        assert_eq!(nodes[1].offset_emissions, Co2e::from_kilos(3.0));
+        assert_eq!(nodes[1].total_emissions, Co2e::from_kilos(12.0));
+        assert_eq!(nodes[0].total_emissions, Co2e::from_kilos(5.0));
+    }
+}
@@ -0,0 +1,140 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+/// Where a node runs and who runs it, keyed by the node id (the node principal).
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NodeMetadata {
+    pub node_id: String,
+    pub node_provider: Option<String>,
+    pub node_operator: Option<String>,
+    pub subnet_id: Option<String>,
+    pub data_center: Option<String>,
+    pub country: Option<String>,
+    pub grid_region: Option<String>,
+    pub hardware_generation: Option<String>,
+}
+
+impl NodeMetadata {
+    // fills the fields this record does not know yet from `other`
+    fn fill_missing(&mut self, other: NodeMetadata) {
+        fn fill(field: &mut Option<String>, value: Option<String>) {
+            if field.is_none() {
+                *field = value;
+            }
+        }
+        fill(&mut self.node_provider, other.node_provider);
+        fill(&mut self.node_operator, other.node_operator);
+        fill(&mut self.subnet_id, other.subnet_id);
+        fill(&mut self.data_center, other.data_center);
+        fill(&mut self.country, other.country);
+        fill(&mut self.grid_region, other.grid_region);
+        fill(&mut self.hardware_generation, other.hardware_generation);
+    }
+
+    // overwrites the fields `other` has a value for
+    fn overwrite(&mut self, other: NodeMetadata) {
+        fn set(field: &mut Option<String>, value: Option<String>) {
+            if value.is_some() {
+                *field = value;
+            }
+        }
+        set(&mut self.node_provider, other.node_provider);
+        set(&mut self.node_operator, other.node_operator);
+        set(&mut self.subnet_id, other.subnet_id);
+        set(&mut self.data_center, other.data_center);
+        set(&mut self.country, other.country);
+        set(&mut self.grid_region, other.grid_region);
+        set(&mut self.hardware_generation, other.hardware_generation);
+    }
+}
+
+thread_local! {
+    static NODE_REGISTRY: RefCell<BTreeMap<String, NodeMetadata>> = RefCell::default();
+}
+
+pub fn registry_snapshot() -> Vec<NodeMetadata> {
+    NODE_REGISTRY.with(|r| r.borrow().values().cloned().collect())
+}
+
+pub fn restore_registry(nodes: Vec<NodeMetadata>) {
+    NODE_REGISTRY.with(|r| {
+        *r.borrow_mut() = nodes
+            .into_iter()
+            .map(|node| (node.node_id.clone(), node))
+            .collect()
+    });
+}
+
+// first string value among the given keys, the emissions backend is not consistent about names
+fn string_field(record: &serde_json::Value, keys: &[&str]) -> Option<String> {
+    keys.iter().find_map(|key| match &record[*key] {
+        serde_json::Value::String(value) if !value.is_empty() => Some(value.clone()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    })
+}
+
+/// Metadata of a node record of the emissions backend, None when it carries none.
+pub fn metadata_from_backend(node_id: &str, record: &serde_json::Value) -> Option<NodeMetadata> {
+    let metadata = NodeMetadata {
+        node_id: node_id.to_string(),
+        node_provider: string_field(record, &["node_provider_id", "node_provider"]),
+        node_operator: string_field(record, &["node_operator_id", "node_operator"]),
+        subnet_id: string_field(record, &["subnet_id", "subnet"]),
+        data_center: string_field(record, &["dc_id", "data_center"]),
+        country: string_field(record, &["country"]),
+        grid_region: string_field(record, &["region", "grid_region"]),
+        hardware_generation: string_field(record, &["node_type", "hardware_generation"]),
+    };
+    let empty = NodeMetadata {
+        node_id: metadata.node_id.clone(),
+        ..Default::default()
+    };
+    (metadata != empty).then_some(metadata)
+}
+
+/// Backend data only fills gaps, values imported by an admin are kept.
+pub fn merge_from_backend(nodes: Vec<NodeMetadata>) {
+    NODE_REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        for node in nodes {
+            match registry.get_mut(&node.node_id) {
+                Some(known) => known.fill_missing(node),
+                None => {
+                    registry.insert(node.node_id.clone(), node);
+                }
+            }
+        }
+    })
+}
+
+/// Admin import, the given fields overwrite what is known about the nodes.
+pub fn import(nodes: Vec<NodeMetadata>) -> Result<u64, String> {
+    if nodes.iter().any(|node| node.node_id.trim().is_empty()) {
+        return Err("Every node needs a node_id".to_string());
+    }
+
+    NODE_REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        for node in &nodes {
+            registry
+                .entry(node.node_id.trim().to_string())
+                .or_insert_with(|| NodeMetadata {
+                    node_id: node.node_id.trim().to_string(),
+                    ..Default::default()
+                })
+                .overwrite(node.clone());
+        }
+    });
+    Ok(nodes.len() as u64)
+}
+
+pub fn remove(node_id: &str) -> Option<NodeMetadata> {
+    NODE_REGISTRY.with(|r| r.borrow_mut().remove(node_id))
+}
+
+pub fn get(node_id: &str) -> Option<NodeMetadata> {
+    NODE_REGISTRY.with(|r| r.borrow().get(node_id).cloned())
+}
@@ -0,0 +1,302 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use candid::{CandidType, Principal};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::units::Co2e;
+
+// identifies a payment of a wallet, the children of a batch payment share the block height
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PaymentKey {
+    pub block_height: u64,
+    pub node_id: Option<String>,
+    pub client: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NodeOffset {
+    pub node: String,
+    pub offset: Co2e,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LedgerEntryKind {
+    Offset,
+    Reversal,
+}
+
+/// Random node selection allocations were made on: the raw_rand seed, how many nodes were picked
+/// and the hash of the candidates with their weights, see `sampling::candidates_hash`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SelectionRecord {
+    pub seed: Vec<u8>,
+    pub sample_size: u64,
+    pub candidates_hash: Vec<u8>,
+}
+
+/// Entry of the append-only offset ledger. A reversal carries the CO2e and allocations of the
+/// offset entry it reverses, which its kind deducts.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LedgerEntry {
+    pub id: u64,
+    pub wallet: Principal,
+    pub payment: PaymentKey,
+    pub kind: LedgerEntryKind,
+    pub co2e: Co2e,
+    pub allocations: Vec<NodeOffset>,
+    pub recorded_at: u64,
+    pub reverses: Option<u64>,
+    pub reason: Option<String>,
+    // seed, sample size and candidates hash of the random node selection the allocations were
+    // made on, if any; entries recorded before the size and hash were kept only have the seed
+    pub random_seed: Option<Vec<u8>>,
+    pub sample_size: Option<u64>,
+    pub candidates_hash: Option<Vec<u8>>,
+    // project the payment funded
+    pub project: Option<String>,
+    // proof of the purchase at the vendor, e.g. the Cawa contribution URL
+    pub proof_url: Option<String>,
+}
+
+// allocation as stored before the ledger was fixed-point, in kilos
+#[derive(CandidType, Deserialize)]
+pub struct LegacyNodeOffset {
+    pub node: String,
+    pub offset: f64,
+}
+
+// entry as stored before the ledger was fixed-point, reversals held negated kilos
+#[derive(CandidType, Deserialize)]
+pub struct LegacyLedgerEntry {
+    pub id: u64,
+    pub wallet: Principal,
+    pub payment: PaymentKey,
+    pub kind: LedgerEntryKind,
+    pub kilos_co2e: f64,
+    pub allocations: Vec<LegacyNodeOffset>,
+    pub recorded_at: u64,
+    pub reverses: Option<u64>,
+    pub reason: Option<String>,
+    pub random_seed: Option<Vec<u8>>,
+    pub sample_size: Option<u64>,
+    pub candidates_hash: Option<Vec<u8>>,
+    pub project: Option<String>,
+    pub proof_url: Option<String>,
+}
+
+impl LegacyLedgerEntry {
+    pub fn migrate(self) -> LedgerEntry {
+        LedgerEntry {
+            id: self.id,
+            wallet: self.wallet,
+            payment: self.payment,
+            kind: self.kind,
+            co2e: Co2e::from_kilos(self.kilos_co2e.abs()),
+            allocations: self
+                .allocations
+                .into_iter()
+                .map(|allocation| NodeOffset {
+                    node: allocation.node,
+                    offset: Co2e::from_kilos(allocation.offset.abs()),
+                })
+                .collect(),
+            recorded_at: self.recorded_at,
+            reverses: self.reverses,
+            reason: self.reason,
+            random_seed: self.random_seed,
+            sample_size: self.sample_size,
+            candidates_hash: self.candidates_hash,
+            project: self.project,
+            proof_url: self.proof_url,
+        }
+    }
+}
+
+type LedgerKey = (Principal, PaymentKey);
+
+thread_local! {
+    static LEDGER: RefCell<BTreeMap<u64, LedgerEntry>> = RefCell::default();
+    // latest entry per payment, rebuilt from LEDGER on upgrade
+    static LATEST_ENTRY: RefCell<BTreeMap<LedgerKey, u64>> = RefCell::default();
+    // payments being applied by an in-flight call
+    static PENDING: RefCell<BTreeSet<LedgerKey>> = RefCell::default();
+}
+
+pub fn ledger_snapshot() -> Vec<LedgerEntry> {
+    LEDGER.with(|l| l.borrow().values().cloned().collect())
+}
+
+pub fn restore_ledger(entries: Vec<LedgerEntry>) {
+    let mut ledger = BTreeMap::new();
+    let mut latest = BTreeMap::new();
+    for entry in entries {
+        latest.insert((entry.wallet, entry.payment.clone()), entry.id);
+        ledger.insert(entry.id, entry);
+    }
+    LEDGER.with(|l| *l.borrow_mut() = ledger);
+    LATEST_ENTRY.with(|l| *l.borrow_mut() = latest);
+}
+
+fn latest_entry(wallet: Principal, payment: &PaymentKey) -> Option<LedgerEntry> {
+    let id = LATEST_ENTRY.with(|l| l.borrow().get(&(wallet, payment.clone())).copied())?;
+    LEDGER.with(|l| l.borrow().get(&id).cloned())
+}
+
+fn append(mut entry: LedgerEntry) -> LedgerEntry {
+    LEDGER.with(|l| {
+        let mut ledger = l.borrow_mut();
+        entry.id = ledger.keys().next_back().map_or(1, |id| id + 1);
+        ledger.insert(entry.id, entry.clone());
+    });
+    LATEST_ENTRY.with(|l| {
+        l.borrow_mut()
+            .insert((entry.wallet, entry.payment.clone()), entry.id)
+    });
+    entry
+}
+
+/// Whether the ledger holds an entry for the payment, applied or reversed.
+pub fn is_recorded(wallet: Principal, payment: &PaymentKey) -> bool {
+    LATEST_ENTRY.with(|l| l.borrow().contains_key(&(wallet, payment.clone())))
+}
+
+/// Payment reserved by the call applying it. The claim is released when it is dropped, which also
+/// happens when the call traps after an await.
+pub struct Claim {
+    wallet: Principal,
+    payment: PaymentKey,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        PENDING.with(|p| p.borrow_mut().remove(&(self.wallet, self.payment.clone())));
+    }
+}
+
+/// Reserves a payment for the caller that is about to apply it. Payments that were applied,
+/// reversed or are being applied by another call are rejected.
+pub fn claim(wallet: Principal, payment: &PaymentKey) -> Result<Claim, String> {
+    match latest_entry(wallet, payment).map(|entry| entry.kind) {
+        Some(LedgerEntryKind::Offset) => return Err("Payment was already applied".to_string()),
+        Some(LedgerEntryKind::Reversal) => return Err("Payment was reversed".to_string()),
+        None => {}
+    }
+    if !PENDING.with(|p| p.borrow_mut().insert((wallet, payment.clone()))) {
+        return Err("Payment is being applied".to_string());
+    }
+    Ok(Claim {
+        wallet,
+        payment: payment.clone(),
+    })
+}
+
+/// Records the allocations of a claimed payment.
+pub fn record_offset(
+    claim: Claim,
+    co2e: Co2e,
+    allocations: Vec<NodeOffset>,
+    selection: Option<SelectionRecord>,
+    project: Option<String>,
+    proof_url: Option<String>,
+) -> LedgerEntry {
+    append(LedgerEntry {
+        id: 0,
+        wallet: claim.wallet,
+        payment: claim.payment.clone(),
+        kind: LedgerEntryKind::Offset,
+        co2e,
+        allocations,
+        recorded_at: ic_cdk::api::time(),
+        reverses: None,
+        reason: None,
+        sample_size: selection.as_ref().map(|selection| selection.sample_size),
+        candidates_hash: selection.as_ref().map(|selection| selection.candidates_hash.clone()),
+        random_seed: selection.map(|selection| selection.seed),
+        project,
+        proof_url,
+    })
+}
+
+/// Appends a reversal of the offset recorded for a payment, e.g. after a refund.
+pub fn record_reversal(
+    wallet: Principal,
+    payment: PaymentKey,
+    reason: String,
+) -> Result<LedgerEntry, String> {
+    let offset = match latest_entry(wallet, &payment) {
+        Some(entry) if entry.kind == LedgerEntryKind::Offset => entry,
+        Some(_) => return Err("Payment was already reversed".to_string()),
+        None => return Err("Payment was never applied".to_string()),
+    };
+
+    Ok(append(LedgerEntry {
+        id: 0,
+        wallet,
+        payment,
+        kind: LedgerEntryKind::Reversal,
+        co2e: offset.co2e,
+        allocations: offset.allocations.clone(),
+        recorded_at: ic_cdk::api::time(),
+        reverses: Some(offset.id),
+        reason: Some(reason),
+        random_seed: offset.random_seed.clone(),
+        sample_size: offset.sample_size,
+        candidates_hash: offset.candidates_hash.clone(),
+        project: offset.project.clone(),
+        proof_url: offset.proof_url.clone(),
+    }))
+}
+
+/// Entries after `start` (exclusive), oldest first.
+pub fn entries(start: Option<u64>, limit: usize) -> Vec<LedgerEntry> {
+    let from = start.map_or(0, |id| id + 1);
+    LEDGER.with(|l| l.borrow().range(from..).take(limit).map(|(_, e)| e.clone()).collect())
+}
+
+/// Entries recorded between two timestamps (inclusive).
+pub fn entries_between(from: Option<u64>, to: Option<u64>) -> Vec<LedgerEntry> {
+    let (from, to) = (from.unwrap_or(0), to.unwrap_or(u64::MAX));
+    LEDGER.with(|l| {
+        l.borrow()
+            .values()
+            .filter(|entry| (from..=to).contains(&entry.recorded_at))
+            .cloned()
+            .collect()
+    })
+}
+
+/// All entries recorded for one payment of a wallet.
+pub fn payment_entries(wallet: Principal, payment: &PaymentKey) -> Vec<LedgerEntry> {
+    LEDGER.with(|l| {
+        l.borrow()
+            .values()
+            .filter(|entry| entry.wallet == wallet && &entry.payment == payment)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Project of the latest offset that funded one.
+pub fn latest_project() -> Option<String> {
+    LEDGER.with(|l| {
+        l.borrow()
+            .values()
+            .rev()
+            .filter(|entry| entry.kind == LedgerEntryKind::Offset)
+            .find_map(|entry| entry.project.clone())
+    })
+}
+
+/// Offset and reversal entries of the payments that funded a project.
+pub fn project_entries(project: &str) -> Vec<LedgerEntry> {
+    LEDGER.with(|l| {
+        l.borrow()
+            .values()
+            .filter(|entry| entry.project.as_deref() == Some(project))
+            .cloned()
+            .collect()
+    })
+}
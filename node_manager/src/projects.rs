@@ -0,0 +1,294 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::units::Co2e;
+
+const MAX_ID_LENGTH: usize = 64;
+const MAX_NAME_LENGTH: usize = 200;
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+const MIN_VINTAGE: u16 = 1990;
+const MAX_VINTAGE: u16 = 2100;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ProjectStatus {
+    Active,
+    // kept for the payments that funded it, new offsets should not go to it
+    Retired,
+}
+
+/// Offset project payments can fund, `vendor_ref` is the id of the project at the vendor.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Project {
+    pub id: String,
+    pub name: String,
+    pub vendor: Option<String>,
+    pub vendor_ref: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub methodology: Option<String>,
+    pub vintage: Option<u16>,
+    pub certification_standard: Option<String>,
+    pub icon: Option<String>,
+    pub status: ProjectStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// Fields of a project an admin can set.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ProjectInput {
+    pub id: String,
+    pub name: String,
+    pub vendor: Option<String>,
+    pub vendor_ref: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub methodology: Option<String>,
+    pub vintage: Option<u16>,
+    pub certification_standard: Option<String>,
+    pub icon: Option<String>,
+}
+
+/// Payments that funded a project, net of reversals.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ProjectFunding {
+    pub project_id: String,
+    pub payment_count: u64,
+    pub co2e: Co2e,
+    pub ledger_entries: Vec<u64>,
+}
+
+// project as stored before projects had their own id, `id` held the ids it was known by
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LegacyProject {
+    pub id: Vec<String>,
+    pub name: String,
+    pub icon: Option<String>,
+}
+
+impl LegacyProject {
+    // the first id becomes the project id, a second one is the id at the vendor
+    pub fn migrate(self) -> Project {
+        Project {
+            id: self.id.first().cloned().unwrap_or_else(|| self.name.clone()),
+            vendor_ref: self.id.get(1).cloned(),
+            name: self.name,
+            vendor: None,
+            description: None,
+            location: None,
+            methodology: None,
+            vintage: None,
+            certification_standard: None,
+            icon: self.icon,
+            status: ProjectStatus::Active,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+}
+
+thread_local! {
+    static PROJECTS: RefCell<BTreeMap<String, Project>> = RefCell::default();
+}
+
+pub fn projects_snapshot() -> Vec<Project> {
+    PROJECTS.with(|p| p.borrow().values().cloned().collect())
+}
+
+pub fn restore_projects(projects: Vec<Project>) {
+    PROJECTS.with(|p| {
+        *p.borrow_mut() = projects
+            .into_iter()
+            .map(|project| (project.id.clone(), project))
+            .collect()
+    });
+}
+
+fn validate(input: &ProjectInput) -> Result<(), String> {
+    if input.id.is_empty() || input.id.len() > MAX_ID_LENGTH {
+        return Err(format!("Project id must have 1 to {} characters", MAX_ID_LENGTH));
+    }
+    if !input
+        .id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("Project id may only contain letters, digits, '-' and '_'".to_string());
+    }
+    if input.name.trim().is_empty() || input.name.len() > MAX_NAME_LENGTH {
+        return Err(format!("Project name must have 1 to {} characters", MAX_NAME_LENGTH));
+    }
+    if input
+        .description
+        .as_ref()
+        .is_some_and(|description| description.len() > MAX_DESCRIPTION_LENGTH)
+    {
+        return Err(format!("Description can have at most {} characters", MAX_DESCRIPTION_LENGTH));
+    }
+    if input
+        .vintage
+        .is_some_and(|vintage| !(MIN_VINTAGE..=MAX_VINTAGE).contains(&vintage))
+    {
+        return Err(format!("Vintage must be between {} and {}", MIN_VINTAGE, MAX_VINTAGE));
+    }
+    if input
+        .icon
+        .as_ref()
+        .is_some_and(|icon| !icon.starts_with("https://") && !icon.starts_with("data:image/"))
+    {
+        return Err("Icon must be an https or data:image URL".to_string());
+    }
+    Ok(())
+}
+
+fn apply(project: &mut Project, input: ProjectInput) {
+    project.name = input.name.trim().to_string();
+    project.vendor = input.vendor;
+    project.vendor_ref = input.vendor_ref;
+    project.description = input.description;
+    project.location = input.location;
+    project.methodology = input.methodology;
+    project.vintage = input.vintage;
+    project.certification_standard = input.certification_standard;
+    project.icon = input.icon;
+    project.updated_at = ic_cdk::api::time();
+}
+
+pub fn create(input: ProjectInput) -> Result<Project, String> {
+    validate(&input)?;
+    if get(&input.id).is_some() {
+        return Err(format!("Project {} already exists", input.id));
+    }
+
+    let now = ic_cdk::api::time();
+    let mut project = Project {
+        id: input.id.clone(),
+        name: String::new(),
+        vendor: None,
+        vendor_ref: None,
+        description: None,
+        location: None,
+        methodology: None,
+        vintage: None,
+        certification_standard: None,
+        icon: None,
+        status: ProjectStatus::Active,
+        created_at: now,
+        updated_at: now,
+    };
+    apply(&mut project, input);
+    PROJECTS.with(|p| p.borrow_mut().insert(project.id.clone(), project.clone()));
+    Ok(project)
+}
+
+pub fn update(input: ProjectInput) -> Result<Project, String> {
+    validate(&input)?;
+    PROJECTS.with(|p| {
+        let mut projects = p.borrow_mut();
+        let project = projects
+            .get_mut(&input.id)
+            .ok_or_else(|| format!("Project {} not found", input.id))?;
+        apply(project, input);
+        Ok(project.clone())
+    })
+}
+
+pub fn set_status(id: &str, status: ProjectStatus) -> Result<Project, String> {
+    PROJECTS.with(|p| {
+        let mut projects = p.borrow_mut();
+        let project = projects
+            .get_mut(id)
+            .ok_or_else(|| format!("Project {} not found", id))?;
+        project.status = status;
+        project.updated_at = ic_cdk::api::time();
+        Ok(project.clone())
+    })
+}
+
+pub fn remove(id: &str) -> Option<Project> {
+    PROJECTS.with(|p| p.borrow_mut().remove(id))
+}
+
+/// Removes the projects `keep` returns false for, returns how many were removed.
+pub fn retain(keep: impl Fn(&Project) -> bool) -> u64 {
+    PROJECTS.with(|p| {
+        let mut projects = p.borrow_mut();
+        let before = projects.len();
+        projects.retain(|_, project| keep(project));
+        (before - projects.len()) as u64
+    })
+}
+
+pub fn get(id: &str) -> Option<Project> {
+    PROJECTS.with(|p| p.borrow().get(id).cloned())
+}
+
+/// Id of the project a payment funds, looked up by its id or by its id at the vendor. Retired
+/// projects are rejected, a project nobody registered yet funds nothing but does not hold the
+/// payment back.
+pub fn funded(reference: &str, vendor: Option<&str>) -> Result<Option<String>, String> {
+    let Some(project) = get(reference).or_else(|| {
+        PROJECTS.with(|p| {
+            p.borrow()
+                .values()
+                .find(|project| {
+                    project.vendor_ref.as_deref() == Some(reference)
+                        && (vendor.is_none() || project.vendor.as_deref() == vendor)
+                })
+                .cloned()
+        })
+    }) else {
+        return Ok(None);
+    };
+    match project.status {
+        ProjectStatus::Active => Ok(Some(project.id)),
+        ProjectStatus::Retired => Err(format!("Project {} is retired", project.id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(id: &str, vendor_ref: &str, status: ProjectStatus) -> Project {
+        Project {
+            id: id.to_string(),
+            name: id.to_string(),
+            vendor: Some("Cawa".to_string()),
+            vendor_ref: Some(vendor_ref.to_string()),
+            description: None,
+            location: None,
+            methodology: None,
+            vintage: None,
+            certification_standard: None,
+            icon: None,
+            status,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn active_projects_are_funded_by_id_or_vendor_ref() {
+        restore_projects(vec![project("forest", "018828f6", ProjectStatus::Active)]);
+        assert_eq!(funded("forest", None), Ok(Some("forest".to_string())));
+        assert_eq!(funded("018828f6", Some("Cawa")), Ok(Some("forest".to_string())));
+        // the vendor ref only counts for the vendor that issued it
+        assert_eq!(funded("018828f6", Some("Other")), Ok(None));
+    }
+
+    #[test]
+    fn retired_projects_are_rejected() {
+        restore_projects(vec![project("forest", "018828f6", ProjectStatus::Retired)]);
+        assert!(funded("forest", None).is_err());
+        assert!(funded("018828f6", Some("Cawa")).is_err());
+    }
+
+    #[test]
+    fn unknown_projects_fund_nothing() {
+        restore_projects(vec![]);
+        assert_eq!(funded("forest", Some("Cawa")), Ok(None));
+    }
+}
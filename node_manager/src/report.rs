@@ -0,0 +1,258 @@
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::balance;
+use crate::clients::ClientRecord;
+use crate::history;
+use crate::node_registry;
+use crate::offset_ledger::{LedgerEntry, LedgerEntryKind};
+use crate::projects;
+use crate::units::Co2e;
+
+const METHODOLOGY: &str = "Emissions are attributed to the client per node it runs on. The \
+emissions of a node over the period are the difference of its cumulative emissions at the end of \
+the period and before its start, taken from daily snapshots of the emissions sync. The client \
+does not own or control the nodes or buy their electricity, so all of it is reported as GHG \
+Protocol Scope 3, Category 1 (purchased goods and services). Offsets are the entries of the \
+offset ledger for payments made for the client, or for payments without a client the share \
+allocated to its nodes; reversals are deducted.";
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum GhgScope {
+    Scope1,
+    Scope2,
+    Scope3,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ScopeEmissions {
+    pub scope: GhgScope,
+    pub category: String,
+    pub emissions: Co2e,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NodeReportLine {
+    pub node_id: String,
+    pub subnet_id: Option<String>,
+    pub data_center: Option<String>,
+    pub country: Option<String>,
+    pub emissions: Co2e,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OffsetReportLine {
+    pub ledger_entry: u64,
+    pub recorded_at: u64,
+    pub kind: LedgerEntryKind,
+    pub co2e: Co2e,
+    pub project: Option<String>,
+    pub vendor: Option<String>,
+    pub certification_standard: Option<String>,
+    pub vintage: Option<u16>,
+    pub proof_url: Option<String>,
+}
+
+/// Emissions and offsets of a client over a period, laid out along the GHG Protocol scopes.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EsgReport {
+    pub client: String,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub generated_at: u64,
+    pub scopes: Vec<ScopeEmissions>,
+    pub nodes: Vec<NodeReportLine>,
+    pub offsets: Vec<OffsetReportLine>,
+    pub total_emissions: Co2e,
+    // offsets minus reversals
+    pub total_offsets: Co2e,
+    pub net_emissions: Co2e,
+    pub methodology: String,
+    pub data_sources: Vec<String>,
+}
+
+pub fn build(
+    client: &ClientRecord,
+    from: Option<u64>,
+    to: Option<u64>,
+    entries: &[LedgerEntry],
+    data_sources: Vec<String>,
+    now: u64,
+) -> EsgReport {
+    let nodes: Vec<NodeReportLine> = client
+        .node_ids
+        .iter()
+        .map(|node_id| {
+            let metadata = node_registry::get(node_id).unwrap_or_default();
+            NodeReportLine {
+                node_id: node_id.clone(),
+                subnet_id: metadata.subnet_id,
+                data_center: metadata.data_center,
+                country: metadata.country,
+                emissions: history::emitted_between(std::slice::from_ref(node_id), from, to),
+            }
+        })
+        .collect();
+    let total_emissions: Co2e = nodes.iter().map(|node| node.emissions).sum();
+
+    let offsets: Vec<OffsetReportLine> = entries
+        .iter()
+        .filter_map(|entry| {
+            let co2e = balance::attributed_co2e(&client.name, &client.node_ids, entry)?;
+            let project = entry.project.as_deref().and_then(projects::get);
+            Some(OffsetReportLine {
+                ledger_entry: entry.id,
+                recorded_at: entry.recorded_at,
+                kind: entry.kind.clone(),
+                co2e,
+                project: entry.project.clone(),
+                vendor: project.as_ref().and_then(|project| project.vendor.clone()),
+                certification_standard: project
+                    .as_ref()
+                    .and_then(|project| project.certification_standard.clone()),
+                vintage: project.and_then(|project| project.vintage),
+                proof_url: entry.proof_url.clone(),
+            })
+        })
+        .filter(|line| !line.co2e.is_zero())
+        .collect();
+    let (offset, reversed) = offsets.iter().fold((Co2e::ZERO, Co2e::ZERO), |(o, r), line| {
+        match line.kind {
+            LedgerEntryKind::Offset => (o + line.co2e, r),
+            LedgerEntryKind::Reversal => (o, r + line.co2e),
+        }
+    });
+    let total_offsets = offset - reversed;
+
+    EsgReport {
+        client: client.name.clone(),
+        from,
+        to,
+        generated_at: now,
+        scopes: vec![
+            ScopeEmissions {
+                scope: GhgScope::Scope1,
+                category: "Direct emissions".to_string(),
+                emissions: Co2e::ZERO,
+            },
+            ScopeEmissions {
+                scope: GhgScope::Scope2,
+                category: "Purchased electricity".to_string(),
+                emissions: Co2e::ZERO,
+            },
+            ScopeEmissions {
+                scope: GhgScope::Scope3,
+                category: "Category 1: Purchased goods and services (Internet Computer nodes)"
+                    .to_string(),
+                emissions: total_emissions,
+            },
+        ],
+        nodes,
+        offsets,
+        total_emissions,
+        total_offsets,
+        net_emissions: total_emissions - total_offsets,
+        methodology: METHODOLOGY.to_string(),
+        data_sources,
+    }
+}
+
+// Quotes a CSV field when it has to be. Text a spreadsheet would read as a formula gets a `'`
+// in front, the quantities of the report are never negative so no number starts with `-`.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn csv_row(fields: &[String]) -> String {
+    let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    fields.join(",") + "\n"
+}
+
+/// The report as one CSV table, a row per scope, node, offset and total. Quantities are in kg.
+pub fn to_csv(report: &EsgReport) -> String {
+    let optional = |value: &Option<String>| value.clone().unwrap_or_default();
+    let kilos = |co2e: Co2e| co2e.kilos().to_string();
+
+    let mut csv = csv_row(
+        &[
+            "record_type", "scope", "category", "node_id", "subnet_id", "country", "ledger_entry",
+            "recorded_at", "project", "vendor", "certification_standard", "vintage", "proof_url",
+            "kg_co2e",
+        ]
+        .map(String::from),
+    );
+    for scope in &report.scopes {
+        let mut row = vec![String::new(); 14];
+        row[0] = "scope".to_string();
+        row[1] = format!("{:?}", scope.scope);
+        row[2] = scope.category.clone();
+        row[13] = kilos(scope.emissions);
+        csv += &csv_row(&row);
+    }
+    for node in &report.nodes {
+        let mut row = vec![String::new(); 14];
+        row[0] = "node".to_string();
+        row[1] = format!("{:?}", GhgScope::Scope3);
+        row[3] = node.node_id.clone();
+        row[4] = optional(&node.subnet_id);
+        row[5] = optional(&node.country);
+        row[13] = kilos(node.emissions);
+        csv += &csv_row(&row);
+    }
+    for offset in &report.offsets {
+        let mut row = vec![String::new(); 14];
+        row[0] = match offset.kind {
+            LedgerEntryKind::Offset => "offset".to_string(),
+            LedgerEntryKind::Reversal => "reversal".to_string(),
+        };
+        row[6] = offset.ledger_entry.to_string();
+        row[7] = offset.recorded_at.to_string();
+        row[8] = optional(&offset.project);
+        row[9] = optional(&offset.vendor);
+        row[10] = optional(&offset.certification_standard);
+        row[11] = offset.vintage.map(|vintage| vintage.to_string()).unwrap_or_default();
+        row[12] = optional(&offset.proof_url);
+        row[13] = kilos(offset.co2e);
+        csv += &csv_row(&row);
+    }
+    for (record_type, co2e) in [
+        ("total_emissions", report.total_emissions),
+        ("total_offsets", report.total_offsets),
+        ("net_emissions", report.net_emissions),
+    ] {
+        let mut row = vec![String::new(); 14];
+        row[0] = record_type.to_string();
+        row[13] = kilos(co2e);
+        csv += &csv_row(&row);
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_a_spreadsheet_would_evaluate_are_escaped() {
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("-1+2"), "'-1+2");
+        assert_eq!(csv_field("https://proof"), "https://proof");
+        assert_eq!(csv_field("1.5"), "1.5");
+    }
+}
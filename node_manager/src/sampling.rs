@@ -0,0 +1,54 @@
+use sha2::{Digest, Sha256};
+
+const SAMPLING_DOMAIN: &[u8] = b"icfootprint-node-sampling";
+const CANDIDATES_DOMAIN: &[u8] = b"icfootprint-sampling-candidates";
+
+// Uniform number in (0, 1] derived from the seed and the node name, so a sample only depends on
+// the seed and the candidates, not on the order they are listed in.
+fn uniform(seed: &[u8], name: &str) -> f64 {
+    let mut hasher = Sha256::new();
+    hasher.update(SAMPLING_DOMAIN);
+    hasher.update((seed.len() as u64).to_le_bytes());
+    hasher.update(seed);
+    hasher.update(name.as_bytes());
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    // 53 bits fit the mantissa of an f64 exactly
+    ((u64::from_be_bytes(bytes) >> 11) + 1) as f64 / (1u64 << 53) as f64
+}
+
+/// Picks up to `sample_size` candidates without replacement, weighted by their emissions
+/// (Efraimidis-Spirakis: every candidate gets the key u^(1/weight), the largest keys win).
+/// Returns the indices of the picked candidates, heaviest key first. Candidates without
+/// emissions are never picked.
+pub fn weighted_sample(seed: &[u8], candidates: &[(String, f64)], sample_size: usize) -> Vec<usize> {
+    let mut keys: Vec<(f64, usize)> = candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, weight))| *weight > 0.0)
+        .map(|(i, (name, weight))| (uniform(seed, name).ln() / weight, i))
+        .collect();
+    // ln(u) / w is monotonic in u^(1/w) and does not underflow for small weights
+    keys.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+    keys.into_iter().take(sample_size).map(|(_, i)| i).collect()
+}
+
+/// SHA-256 of the candidates of a sample and their weights, in name order, so a recorded
+/// selection can be checked against the candidates it claims to be drawn from.
+pub fn candidates_hash(candidates: &[(String, f64)]) -> Vec<u8> {
+    let mut sorted: Vec<&(String, f64)> = candidates.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut hasher = Sha256::new();
+    hasher.update(CANDIDATES_DOMAIN);
+    for (name, weight) in sorted {
+        hasher.update((name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update(weight.to_le_bytes());
+    }
+    hasher.finalize().to_vec()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,191 @@
+use std::{
+    fmt,
+    iter::Sum,
+    ops::{Add, AddAssign, Sub, SubAssign},
+};
+
+use candid::CandidType;
+use serde_derive::{Deserialize, Serialize};
+
+const GRAMS_PER_KILO: f64 = 1_000.0;
+const GRAMS_PER_TONNE: f64 = 1_000_000.0;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Co2eUnit {
+    Grams,
+    Kilograms,
+    Tonnes,
+}
+
+impl Co2eUnit {
+    fn grams(self) -> f64 {
+        match self {
+            Co2eUnit::Grams => 1.0,
+            Co2eUnit::Kilograms => GRAMS_PER_KILO,
+            Co2eUnit::Tonnes => GRAMS_PER_TONNE,
+        }
+    }
+}
+
+/// Quantity of CO2e, stored as whole grams so sums and differences are exact. Arithmetic
+/// saturates, a quantity is never negative.
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct Co2e {
+    pub grams: u64,
+}
+
+impl Co2e {
+    pub const ZERO: Co2e = Co2e { grams: 0 };
+
+    pub fn from_grams(grams: u64) -> Co2e {
+        Co2e { grams }
+    }
+
+    /// Converts a value in `unit`, rounded to the nearest gram.
+    pub fn from_unit(value: f64, unit: Co2eUnit) -> Result<Co2e, String> {
+        if !value.is_finite() || value < 0.0 {
+            return Err(format!("Invalid CO2e quantity {}", value));
+        }
+        let grams = (value * unit.grams()).round();
+        if grams > u64::MAX as f64 {
+            return Err(format!("CO2e quantity {} is too large", value));
+        }
+        Ok(Co2e {
+            grams: grams as u64,
+        })
+    }
+
+    /// Kilos computed elsewhere, e.g. by the emissions backend; invalid values count as zero.
+    pub fn from_kilos(kilos: f64) -> Co2e {
+        Co2e::from_unit(kilos, Co2eUnit::Kilograms).unwrap_or(Co2e::ZERO)
+    }
+
+    pub fn in_unit(self, unit: Co2eUnit) -> f64 {
+        self.grams as f64 / unit.grams()
+    }
+
+    pub fn kilos(self) -> f64 {
+        self.in_unit(Co2eUnit::Kilograms)
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.grams == 0
+    }
+}
+
+impl fmt::Display for Co2e {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} kg CO2e", self.kilos())
+    }
+}
+
+impl Add for Co2e {
+    type Output = Co2e;
+
+    fn add(self, other: Co2e) -> Co2e {
+        Co2e::from_grams(self.grams.saturating_add(other.grams))
+    }
+}
+
+impl AddAssign for Co2e {
+    fn add_assign(&mut self, other: Co2e) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Co2e {
+    type Output = Co2e;
+
+    fn sub(self, other: Co2e) -> Co2e {
+        Co2e::from_grams(self.grams.saturating_sub(other.grams))
+    }
+}
+
+impl SubAssign for Co2e {
+    fn sub_assign(&mut self, other: Co2e) {
+        *self = *self - other;
+    }
+}
+
+impl Sum for Co2e {
+    fn sum<I: Iterator<Item = Co2e>>(iter: I) -> Co2e {
+        iter.fold(Co2e::ZERO, Add::add)
+    }
+}
+
+/// Turns shares in kilos into whole grams that add up to `total`, or to what the caps leave room
+/// for. Grams lost to rounding go to the shares with the largest remainders.
+pub fn apportion(shares: &[f64], total: Co2e, caps: &[Co2e]) -> Vec<Co2e> {
+    let exact: Vec<f64> = shares
+        .iter()
+        .map(|share| share.max(0.0) * GRAMS_PER_KILO)
+        .collect();
+    let mut grams: Vec<Co2e> = exact
+        .iter()
+        .zip(caps)
+        .map(|(share, cap)| Co2e::from_grams(share.floor() as u64).min(*cap))
+        .collect();
+
+    let target = total.min(caps.iter().copied().sum());
+    let mut left = target - grams.iter().copied().sum();
+    let mut order: Vec<usize> = (0..grams.len()).collect();
+    order.sort_by(|&a, &b| (exact[b] - exact[b].floor()).total_cmp(&(exact[a] - exact[a].floor())));
+    // when the shares sum to less than the total the gap can be large, so it is handed out evenly
+    // in one step per pass; every pass either fills a cap or leaves fewer grams than shares
+    while !left.is_zero() {
+        let eligible: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|&i| grams[i] < caps[i] && exact[i] > 0.0)
+            .collect();
+        if eligible.is_empty() {
+            break;
+        }
+        let each = left.grams / eligible.len() as u64;
+        if each == 0 {
+            for &i in eligible.iter().take(left.grams as usize) {
+                grams[i] += Co2e::from_grams(1);
+            }
+            break;
+        }
+        for &i in &eligible {
+            let added = Co2e::from_grams(each).min(caps[i] - grams[i]);
+            grams[i] += added;
+            left -= added;
+        }
+    }
+    grams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_CAP: Co2e = Co2e { grams: u64::MAX };
+
+    #[test]
+    fn rounding_goes_to_the_largest_remainders() {
+        let grams = apportion(&[0.0012, 0.0027], Co2e::from_grams(4), &[NO_CAP, NO_CAP]);
+        assert_eq!(grams, [Co2e::from_grams(1), Co2e::from_grams(3)]);
+    }
+
+    #[test]
+    fn under_summed_shares_are_topped_up_evenly() {
+        let total = Co2e::from_kilos(1_000_000.0);
+        let grams = apportion(&[1.0, 1.0, 0.0], total, &[NO_CAP, NO_CAP, NO_CAP]);
+        assert_eq!(grams, [Co2e::from_kilos(500_000.0), Co2e::from_kilos(500_000.0), Co2e::ZERO]);
+    }
+
+    #[test]
+    fn caps_are_respected() {
+        let caps = [Co2e::from_kilos(2.0), NO_CAP];
+        let grams = apportion(&[0.001, 0.001], Co2e::from_kilos(10.0), &caps);
+        assert_eq!(grams, [Co2e::from_kilos(2.0), Co2e::from_kilos(8.0)]);
+
+        let caps = [Co2e::from_kilos(2.0), Co2e::from_kilos(3.0)];
+        let grams = apportion(&[1.0, 1.0], Co2e::from_kilos(10.0), &caps);
+        assert_eq!(grams, caps);
+    }
+}